@@ -0,0 +1,65 @@
+#!/bin/sh
+# Runs the bot with discovery in a network namespace, as in the README, and checks that it is
+# advertised over mDNS and that picking it in the Spotify app logs it in.
+#
+# Needs root, avahi-browse with avahi-daemon running on the host, curl, and DISCORD_TOKEN and
+# DISCORD_USER_ID in the environment. CACHE_DIR is left unset, so there are no cached credentials
+# and the bot has to wait for the app.
+set -eu
+
+NS=musy
+PORT=4070
+ADDR=10.200.0.2
+LOG=$(mktemp)
+
+cleanup() {
+  [ -n "${BOT:-}" ] && kill "$BOT" 2>/dev/null || true
+  ip netns del "$NS" 2>/dev/null || true
+  ip link del veth-host 2>/dev/null || true
+  rm -f "$LOG"
+}
+trap cleanup EXIT
+
+fail() {
+  echo "FAIL: $1"
+  echo "--- bot log"
+  cat "$LOG"
+  exit 1
+}
+
+cargo build
+
+ip netns add "$NS"
+ip link add veth-musy type veth peer name veth-host
+ip link set veth-musy netns "$NS"
+ip addr add 10.200.0.1/24 dev veth-host && ip link set veth-host up
+ip netns exec "$NS" ip addr add "$ADDR/24" dev veth-musy
+ip netns exec "$NS" ip link set veth-musy up && ip netns exec "$NS" ip link set lo up
+
+ip netns exec "$NS" env -u CACHE_DIR DISCOVERY=true DISCOVERY_PORT="$PORT" \
+  ./target/debug/rust-music-bot >"$LOG" 2>&1 &
+BOT=$!
+
+# The zeroconf endpoint the app talks to
+for _ in $(seq 30); do
+  curl -sf "http://$ADDR:$PORT/?action=getInfo" | grep -q deviceID && break
+  sleep 1
+done
+curl -sf "http://$ADDR:$PORT/?action=getInfo" | grep -q deviceID \
+  || fail "no zeroconf endpoint on $ADDR:$PORT"
+echo "ok: zeroconf endpoint answers on $ADDR:$PORT"
+
+# The advertisement the app finds it by
+avahi-browse -rtp _spotify-connect._tcp | grep -q "$ADDR;$PORT" \
+  || fail "not advertised over mDNS"
+echo "ok: advertised as _spotify-connect._tcp on $ADDR:$PORT"
+
+# The hand-off needs a real account, so this part is manual
+echo "Pick the device in the Spotify app, on a network that reaches $ADDR, within 2 minutes"
+for _ in $(seq 120); do
+  grep -q "Starting client" "$LOG" && break
+  kill -0 "$BOT" 2>/dev/null || fail "bot exited"
+  sleep 1
+done
+grep -q "Starting client" "$LOG" || fail "no login through discovery"
+echo "ok: logged in with the credentials the app handed over"
@@ -0,0 +1,27 @@
+// The bot's modules, as a library so they can be tested from `tests/` without Discord.
+pub mod lib {
+  pub mod announce;
+  pub mod blocklist;
+  pub mod cache;
+  pub mod discovery;
+  pub mod events;
+  pub mod filter;
+  pub mod guilds;
+  pub mod history;
+  pub mod local;
+  pub mod login;
+  pub mod now_playing;
+  pub mod offline;
+  pub mod player;
+  pub mod prefetch;
+  pub mod presence;
+  pub mod queue;
+  pub mod radio;
+  pub mod router;
+  pub mod session;
+  pub mod settings;
+  pub mod snapshot;
+  pub mod source;
+  pub mod stats;
+  pub mod users;
+}
@@ -0,0 +1,131 @@
+use crate::lib::local::LocalLibrary;
+use crate::lib::now_playing::{format_duration, TrackInfo};
+
+use librespot::core::{session::Session, spotify_id::SpotifyId};
+use log::*;
+use serenity::{
+  http::Http,
+  model::{channel::ReactionType, id},
+  prelude::TypeMapKey,
+};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub const LIKE: &str = "👍";
+pub const SKIP: &str = "⏭";
+
+pub struct Announcement {
+  pub track_id: SpotifyId,
+  pub channel_id: id::ChannelId,
+  pub message_id: id::MessageId,
+}
+
+/// Last announcement posted in each guild.
+#[derive(Default)]
+pub struct Announcements {
+  guilds: HashMap<id::GuildId, Announcement>,
+}
+
+impl Announcements {
+  /// Posts a compact embed for `track_id`, unless it is the track announced last in this guild
+  /// (a resume after pause reports the same track as playing again). The lock is only held around
+  /// bookkeeping, so a slow channel does not hold up other guilds.
+  pub async fn announce(
+    announcements: &Mutex<Announcements>,
+    http: &Arc<Http>,
+    session: &Session,
+    library: &LocalLibrary,
+    guild_id: id::GuildId,
+    channel_id: id::ChannelId,
+    track_id: SpotifyId,
+  ) {
+    if let Some(last) = announcements.lock().await.guilds.get(&guild_id) {
+      if last.track_id == track_id {
+        return;
+      }
+    }
+
+    let info = match TrackInfo::lookup(session, library, track_id).await {
+      Ok(info) => info,
+      Err(why) => {
+        debug!("Could not fetch metadata for announcement: {:?}", why);
+        return;
+      }
+    };
+
+    let message = channel_id
+      .send_message(http, |m| {
+        m.embed(|e| {
+          e.title(&info.name);
+          e.description(format!(
+            "{} · {} `{}`",
+            info.artists.join(", "),
+            info.album,
+            format_duration(info.duration_ms)
+          ));
+
+          if let Some(cover_url) = &info.cover_url {
+            e.thumbnail(cover_url);
+          }
+
+          e
+        })
+      })
+      .await;
+
+    let message = match message {
+      Ok(message) => message,
+      Err(why) => {
+        debug!("Error sending announcement: {:?}", why);
+        return;
+      }
+    };
+
+    for reaction in [LIKE, SKIP] {
+      let _ = channel_id
+        .create_reaction(
+          http,
+          message.id,
+          ReactionType::Unicode(reaction.to_string()),
+        )
+        .await;
+    }
+
+    announcements.lock().await.guilds.insert(
+      guild_id,
+      Announcement {
+        track_id,
+        channel_id,
+        message_id: message.id,
+      },
+    );
+  }
+
+  /// Forgets the last announced track, so playing it again after a stop is announced anew.
+  pub fn reset(&mut self, guild_id: id::GuildId) {
+    self.guilds.remove(&guild_id);
+  }
+
+  pub fn is_current(&self, guild_id: id::GuildId, message_id: id::MessageId) -> bool {
+    self
+      .guilds
+      .get(&guild_id)
+      .map_or(false, |last| last.message_id == message_id)
+  }
+}
+
+/// Matches a reaction against one of our emoji, ignoring the variation selector Discord may add.
+pub fn is_reaction(reaction: &ReactionType, emoji: &str) -> bool {
+  match reaction {
+    ReactionType::Unicode(s) => s.trim_end_matches('\u{fe0f}') == emoji,
+    _ => false,
+  }
+}
+
+pub struct AnnouncementsKey;
+
+impl TypeMapKey for AnnouncementsKey {
+  type Value = Arc<Mutex<Announcements>>;
+}
@@ -0,0 +1,77 @@
+use crate::lib::prefetch;
+
+use librespot::core::{
+  session::Session,
+  spotify_id::{SpotifyAudioType, SpotifyId},
+};
+use librespot::metadata::{Album, Artist, Metadata, Track};
+
+use std::collections::BTreeSet;
+
+/// Something a guild can block with `!block`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Blockable {
+  Track(SpotifyId),
+  Artist(SpotifyId),
+  Album(SpotifyId),
+}
+
+impl Blockable {
+  /// Accepts `spotify:artist:<id>` style URIs and open.spotify.com links.
+  pub fn parse(uri: &str) -> Option<Blockable> {
+    let (kind, id) = prefetch::split_uri(uri)?;
+
+    match kind {
+      "track" => Some(Blockable::Track(id)),
+      "artist" => Some(Blockable::Artist(id)),
+      "album" => Some(Blockable::Album(id)),
+      _ => None,
+    }
+  }
+
+  /// How it is stored in the guild's settings.
+  pub fn uri(&self) -> String {
+    match self {
+      Blockable::Track(id) => format!("spotify:track:{}", id.to_base62()),
+      Blockable::Artist(id) => format!("spotify:artist:{}", id.to_base62()),
+      Blockable::Album(id) => format!("spotify:album:{}", id.to_base62()),
+    }
+  }
+
+  pub async fn name(&self, session: &Session) -> Option<String> {
+    match *self {
+      Blockable::Track(id) => Track::get(session, id).await.ok().map(|track| track.name),
+      Blockable::Artist(id) => Artist::get(session, id)
+        .await
+        .ok()
+        .map(|artist| artist.name),
+      Blockable::Album(id) => Album::get(session, id).await.ok().map(|album| album.name),
+    }
+  }
+}
+
+/// What blocks the track, if anything does: the track itself, one of its artists or its album.
+pub async fn blocked(
+  session: &Session,
+  blocked: &BTreeSet<String>,
+  track_id: SpotifyId,
+) -> Option<Blockable> {
+  if blocked.is_empty() || track_id.audio_type != SpotifyAudioType::Track {
+    return None;
+  }
+
+  let is_blocked = |blockable: &Blockable| blocked.contains(&blockable.uri());
+
+  if is_blocked(&Blockable::Track(track_id)) {
+    return Some(Blockable::Track(track_id));
+  }
+
+  let track = Track::get(session, track_id).await.ok()?;
+
+  track
+    .artists
+    .into_iter()
+    .map(Blockable::Artist)
+    .chain(Some(Blockable::Album(track.album)))
+    .find(is_blocked)
+}
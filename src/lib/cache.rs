@@ -0,0 +1,257 @@
+use crate::lib::prefetch;
+use crate::lib::users;
+
+use librespot::core::{
+  cache::Cache,
+  session::Session,
+  spotify_id::{FileId, SpotifyId},
+};
+use librespot::metadata::{Metadata, Track};
+use librespot::playback::config::Bitrate;
+use serenity::prelude::TypeMapKey;
+use tokio::sync::Mutex;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{fs, io};
+
+/// Where librespot caches audio and credentials. Audio is shared between linked users, each has
+/// their own credentials directory.
+#[derive(Clone)]
+pub struct CacheConfig {
+  pub dir: Option<PathBuf>,
+  /// Audio cache size limit in bytes.
+  pub limit: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Purge {
+  Audio,
+  Credentials,
+  Volume,
+}
+
+impl Purge {
+  pub fn parse(purge: &str) -> Option<Purge> {
+    match purge {
+      "audio" => Some(Purge::Audio),
+      "credentials" => Some(Purge::Credentials),
+      "volume" => Some(Purge::Volume),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+  pub bytes: u64,
+  pub files: u64,
+}
+
+impl CacheConfig {
+  /// Opens the cache with credentials in `credentials_dir`, or the cache directory itself.
+  pub fn open(&self, credentials_dir: Option<PathBuf>) -> Option<Cache> {
+    let dir = self.dir.clone()?;
+
+    Cache::new(
+      Some(credentials_dir.unwrap_or_else(|| dir.clone())),
+      Some(dir),
+      self.limit,
+    )
+    .ok()
+  }
+
+  /// Size and number of cached audio files.
+  pub fn usage(&self) -> Usage {
+    let mut usage = Usage::default();
+
+    for dir in self.audio_dirs() {
+      let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => continue,
+      };
+
+      for metadata in entries.filter_map(|entry| entry.ok()?.metadata().ok()) {
+        if metadata.is_file() {
+          usage.bytes += metadata.len();
+          usage.files += 1;
+        }
+      }
+    }
+
+    usage
+  }
+
+  /// Removes cached files of one kind, returning how many were removed. Audio is removed through
+  /// `caches`, those of the running sessions, so their size limits stop counting it. Purged
+  /// credentials are only missed on the next start, running sessions keep theirs in memory.
+  pub fn purge(&self, purge: Purge, caches: &[Cache]) -> io::Result<u64> {
+    let mut removed = 0;
+
+    match purge {
+      Purge::Audio => {
+        let caches = match caches {
+          [] => self.open(None).into_iter().collect(),
+          caches => caches.to_vec(),
+        };
+
+        for file_id in self.audio_files() {
+          // Every cache has to forget the file, only the first finds it on disk
+          let found = caches.iter().fold(false, |found, cache| {
+            cache.remove_file(file_id).is_ok() || found
+          });
+
+          if found {
+            removed += 1;
+          }
+        }
+      }
+      Purge::Credentials | Purge::Volume => {
+        let file_name = match purge {
+          Purge::Credentials => "credentials.json",
+          _ => "volume",
+        };
+
+        for dir in self.credentials_dirs() {
+          match fs::remove_file(dir.join(file_name)) {
+            Ok(()) => removed += 1,
+            Err(why) if why.kind() != io::ErrorKind::NotFound => return Err(why),
+            Err(_) => {}
+          }
+        }
+      }
+    }
+
+    Ok(removed)
+  }
+
+  /// librespot stores audio files in directories named after the first byte of their id.
+  fn audio_dirs(&self) -> Vec<PathBuf> {
+    let entries = match self.dir.as_ref().map(fs::read_dir) {
+      Some(Ok(entries)) => entries,
+      _ => return Vec::new(),
+    };
+
+    entries
+      .filter_map(|entry| entry.ok())
+      .filter(|entry| {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit())
+      })
+      .map(|entry| entry.path())
+      .collect()
+  }
+
+  /// Ids of the cached audio files, librespot names them after the id in hex.
+  fn audio_files(&self) -> Vec<FileId> {
+    let mut files = Vec::new();
+
+    for dir in self.audio_dirs() {
+      let prefix = match dir.file_name() {
+        Some(prefix) => prefix.to_string_lossy().into_owned(),
+        None => continue,
+      };
+
+      let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => continue,
+      };
+
+      for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if let Some(file_id) = parse_file_id(&name) {
+          files.push(file_id);
+        }
+      }
+    }
+
+    files
+  }
+
+  fn credentials_dirs(&self) -> Vec<PathBuf> {
+    let dir = match &self.dir {
+      Some(dir) => dir,
+      None => return Vec::new(),
+    };
+
+    let mut dirs = vec![dir.clone()];
+    dirs.extend(
+      users::cached_user_ids(dir)
+        .into_iter()
+        .map(|user_id| users::credentials_dir(dir, user_id)),
+    );
+
+    dirs
+  }
+}
+
+fn parse_file_id(hex: &str) -> Option<FileId> {
+  if hex.len() != 40 || !hex.is_ascii() {
+    return None;
+  }
+
+  let mut id = [0; 20];
+  for (i, byte) in id.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+  }
+
+  Some(FileId(id))
+}
+
+/// How often a track started from the audio cache rather than being downloaded.
+#[derive(Default)]
+pub struct CacheStats {
+  pub hits: u64,
+  pub misses: u64,
+}
+
+impl CacheStats {
+  pub fn hit_rate(&self) -> Option<f64> {
+    let lookups = self.hits + self.misses;
+
+    if lookups == 0 {
+      return None;
+    }
+
+    Some(self.hits as f64 / lookups as f64)
+  }
+
+  /// Records whether the file the player picks for a loading track, at its bitrate, was already
+  /// cached. Downloads are only written to the cache once complete, so this has to be checked as
+  /// loading starts.
+  pub async fn record(
+    stats: &Mutex<CacheStats>,
+    session: &Session,
+    track_id: SpotifyId,
+    bitrate: Bitrate,
+  ) {
+    let cache = match session.cache() {
+      Some(cache) => cache.clone(),
+      None => return,
+    };
+
+    let track = match Track::get(session, track_id).await {
+      Ok(track) => track,
+      Err(_) => return,
+    };
+
+    let hit = prefetch::pick_file(&track, bitrate)
+      .and_then(|file_id| cache.file_path(file_id))
+      .map_or(false, |path| path.exists());
+
+    let mut stats = stats.lock().await;
+    if hit {
+      stats.hits += 1;
+    } else {
+      stats.misses += 1;
+    }
+  }
+}
+
+pub struct CacheStatsKey;
+
+impl TypeMapKey for CacheStatsKey {
+  type Value = Arc<Mutex<CacheStats>>;
+}
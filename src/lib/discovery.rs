@@ -0,0 +1,73 @@
+use crate::lib::player::{DeviceConfig, SpotifyPlayer};
+
+use anyhow::anyhow;
+use futures::StreamExt;
+use librespot::core::{authentication::Credentials, session::Session};
+use librespot::discovery::Discovery;
+use log::*;
+use tokio::sync::Mutex;
+
+use std::sync::Arc;
+
+/// Advertises the device over zeroconf, so anyone on the local network can pick it in the
+/// Spotify app and log it in with their own account.
+pub fn launch(device: &DeviceConfig, port: u16) -> anyhow::Result<Discovery> {
+  let discovery = Discovery::builder(device.device_id.clone())
+    .name(device.name.clone())
+    .device_type(device.device_type)
+    .port(port)
+    .launch()
+    .map_err(|why| anyhow!("Could not start discovery: {}", why))?;
+
+  info!("Advertising {} on the local network", device.name);
+
+  Ok(discovery)
+}
+
+/// Waits for the first login, so there is a session to start the player with.
+pub async fn first_login(
+  discovery: &mut Discovery,
+  device: &DeviceConfig,
+) -> anyhow::Result<Credentials> {
+  info!(
+    "Waiting for someone to pick {} in the Spotify app",
+    device.name
+  );
+
+  discovery
+    .next()
+    .await
+    .ok_or_else(|| anyhow!("Discovery stopped before anyone logged in"))
+}
+
+/// Switches the player to whoever picks the device next. Their credentials are cached, so a
+/// restart picks up the last account without waiting for discovery.
+pub async fn run(mut discovery: Discovery, player: Arc<Mutex<SpotifyPlayer>>) {
+  while let Some(credentials) = discovery.next().await {
+    info!(
+      "{} picked the device, switching accounts",
+      credentials.username
+    );
+
+    let (session_config, _, cache) = player.lock().await.session_params();
+
+    let session = match Session::connect(session_config, credentials.clone(), cache).await {
+      Ok(session) => session,
+      Err(why) => {
+        warn!("Could not log in {}: {:?}", credentials.username, why);
+        continue;
+      }
+    };
+
+    let mut player = player.lock().await;
+    player.set_credentials(credentials);
+    player.replace_session(session).await;
+    player.status.record("switched account through discovery");
+
+    // The app expects the device it picked to show up right away
+    if player.connect_name().is_none() {
+      let name = player.device().name.clone();
+      player.enable_connect(name).await;
+    }
+  }
+}
@@ -0,0 +1,199 @@
+use librespot::core::spotify_id::SpotifyId;
+use librespot::playback::player::{PlayerEvent, PlayerEventChannel};
+use log::*;
+use serenity::model::id::{GuildId, UserId};
+use serenity::prelude::TypeMapKey;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// Events a subscriber can fall behind by before it starts missing some.
+const CAPACITY: usize = 64;
+
+/// What the Discord side needs from each of librespot's player events. Converting is exhaustive,
+/// so a new librespot event has to be given a meaning here before it builds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+  /// Someone started casting to the device.
+  Started,
+  /// Casting ended, or the track was unloaded.
+  Stopped,
+  Loading {
+    track_id: SpotifyId,
+  },
+  Playing {
+    track_id: SpotifyId,
+    position_ms: u32,
+    duration_ms: u32,
+  },
+  Paused {
+    position_ms: u32,
+  },
+  /// The track was swapped without stopping, e.g. skipping while paused.
+  Changed {
+    new_track_id: SpotifyId,
+  },
+  /// The next track is being loaded ahead of time.
+  Preloading {
+    track_id: SpotifyId,
+  },
+  /// The current track is close to ending, the next one should be preloaded.
+  TimeToPreloadNextTrack,
+  EndOfTrack,
+  /// The track cannot be played, e.g. it is not available in the account's country.
+  Unavailable {
+    track_id: SpotifyId,
+  },
+  /// Volume as set in the Spotify app, 0 to 65535.
+  VolumeSet {
+    volume: u16,
+  },
+}
+
+impl From<PlayerEvent> for Event {
+  fn from(event: PlayerEvent) -> Self {
+    match event {
+      PlayerEvent::Started { .. } => Event::Started,
+      PlayerEvent::Stopped { .. } => Event::Stopped,
+      PlayerEvent::Loading { track_id, .. } => Event::Loading { track_id },
+      PlayerEvent::Playing {
+        track_id,
+        position_ms,
+        duration_ms,
+        ..
+      } => Event::Playing {
+        track_id,
+        position_ms,
+        duration_ms,
+      },
+      PlayerEvent::Paused { position_ms, .. } => Event::Paused { position_ms },
+      PlayerEvent::Changed { new_track_id, .. } => Event::Changed { new_track_id },
+      PlayerEvent::Preloading { track_id } => Event::Preloading { track_id },
+      PlayerEvent::TimeToPreloadNextTrack { .. } => Event::TimeToPreloadNextTrack,
+      PlayerEvent::EndOfTrack { .. } => Event::EndOfTrack,
+      PlayerEvent::Unavailable { track_id, .. } => Event::Unavailable { track_id },
+      PlayerEvent::VolumeSet { volume } => Event::VolumeSet { volume },
+    }
+  }
+}
+
+/// Which of a user's players an event came from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+  /// The player casting is enabled on.
+  Connect,
+  /// The player for tracks we load ourselves.
+  Direct,
+}
+
+/// Events of every librespot player a `SpotifyPlayer` creates. Each new player is forwarded onto
+/// the same bus, so subscribers keep their receiver when casting restarts or the session is
+/// replaced.
+pub struct PlayerEvents {
+  sender: Option<broadcast::Sender<(Source, Event)>>,
+  connect: Option<JoinHandle<()>>,
+  direct: Option<JoinHandle<()>>,
+}
+
+impl PlayerEvents {
+  pub fn new() -> Self {
+    let (sender, _) = broadcast::channel(CAPACITY);
+
+    PlayerEvents {
+      sender: Some(sender),
+      connect: None,
+      direct: None,
+    }
+  }
+
+  /// `None` once the bus is closed.
+  pub fn subscribe(&self) -> Option<broadcast::Receiver<(Source, Event)>> {
+    self.sender.as_ref().map(|sender| sender.subscribe())
+  }
+
+  /// For playback librespot does not do itself, whose events go out as if `source` sent them.
+  pub fn sender(&self) -> Option<broadcast::Sender<(Source, Event)>> {
+    self.sender.clone()
+  }
+
+  /// Forwards a new player's events, replacing the player previously forwarded from `source`.
+  pub fn forward(&mut self, source: Source, mut channel: PlayerEventChannel) {
+    self.stop(source);
+
+    let sender = match &self.sender {
+      Some(sender) => sender.clone(),
+      None => return,
+    };
+
+    let handle = tokio::spawn(async move {
+      // Ends once the player is dropped
+      while let Some(event) = channel.recv().await {
+        // No subscribers is fine, the event is just not needed
+        let _ = sender.send((source, Event::from(event)));
+      }
+    });
+
+    match source {
+      Source::Connect => self.connect = Some(handle),
+      Source::Direct => self.direct = Some(handle),
+    }
+  }
+
+  /// Stops forwarding from `source`, dropping events its player has yet to send.
+  pub fn stop(&mut self, source: Source) {
+    let handle = match source {
+      Source::Connect => self.connect.take(),
+      Source::Direct => self.direct.take(),
+    };
+
+    if let Some(handle) = handle {
+      handle.abort();
+    }
+  }
+
+  /// Stops forwarding altogether, subscribers see the bus close.
+  pub fn close(&mut self) {
+    self.stop(Source::Connect);
+    self.stop(Source::Direct);
+    self.sender = None;
+  }
+}
+
+impl Default for PlayerEvents {
+  fn default() -> Self {
+    PlayerEvents::new()
+  }
+}
+
+/// A player event, routed to the guild it is playing in.
+#[derive(Debug, Clone, Copy)]
+pub struct GuildEvent {
+  pub guild_id: GuildId,
+  pub user_id: UserId,
+  pub source: Source,
+  pub event: Event,
+}
+
+/// Receives the next event, skipping over any the subscriber fell behind on. `None` once the bus
+/// is closed.
+pub async fn next<T: Clone>(receiver: &mut broadcast::Receiver<T>, subscriber: &str) -> Option<T> {
+  loop {
+    match receiver.recv().await {
+      Ok(event) => return Some(event),
+      Err(broadcast::error::RecvError::Lagged(missed)) => {
+        warn!("{} missed {} player events", subscriber, missed);
+      }
+      Err(broadcast::error::RecvError::Closed) => return None,
+    }
+  }
+}
+
+/// Player events of every linked user, once their guild is known.
+pub struct GuildEventsKey;
+
+impl TypeMapKey for GuildEventsKey {
+  type Value = broadcast::Sender<GuildEvent>;
+}
+
+pub fn guild_events() -> broadcast::Sender<GuildEvent> {
+  broadcast::channel(CAPACITY).0
+}
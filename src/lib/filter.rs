@@ -0,0 +1,81 @@
+use crate::lib::blocklist::{self as blocklist, Blockable};
+use crate::lib::local;
+use crate::lib::settings::GuildSettings;
+
+use librespot::core::{
+  mercury::MercuryError,
+  session::Session,
+  spotify_id::{SpotifyAudioType, SpotifyId},
+};
+use librespot::metadata::{Episode, Metadata};
+use librespot::protocol::metadata::Track as TrackMessage;
+use log::*;
+use protobuf::Message;
+
+/// Why a track is kept from playing in a guild.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filtered {
+  Blocked(Blockable),
+  Explicit,
+}
+
+impl Filtered {
+  /// As recorded in the filter log.
+  pub fn reason(&self) -> &'static str {
+    match self {
+      Filtered::Blocked(_) => "blocked",
+      Filtered::Explicit => "explicit",
+    }
+  }
+}
+
+/// Whether the guild's blocklist or explicit filter keeps the track from playing. Tracks whose
+/// metadata can't be fetched are let through.
+pub async fn check(
+  session: &Session,
+  settings: &GuildSettings,
+  track_id: SpotifyId,
+) -> Option<Filtered> {
+  // Local tracks have no Spotify metadata to filter on
+  if local::is_local(track_id) {
+    return None;
+  }
+
+  if let Some(blockable) = blocklist::blocked(session, &settings.blocked, track_id).await {
+    return Some(Filtered::Blocked(blockable));
+  }
+
+  if !settings.skip_explicit {
+    return None;
+  }
+
+  match is_explicit(session, track_id).await {
+    Ok(true) => Some(Filtered::Explicit),
+    Ok(false) => None,
+    Err(why) => {
+      debug!(
+        "Could not tell if {} is explicit: {:?}",
+        track_id.to_base62(),
+        why
+      );
+      None
+    }
+  }
+}
+
+/// librespot's `Track` leaves the explicit flag out, so tracks are read from the raw metadata.
+pub async fn is_explicit(session: &Session, track_id: SpotifyId) -> Result<bool, MercuryError> {
+  if track_id.audio_type == SpotifyAudioType::Podcast {
+    return Ok(Episode::get(session, track_id).await?.explicit);
+  }
+
+  let response = session
+    .mercury()
+    .get(format!("hm://metadata/3/track/{}", track_id.to_base16()))
+    .await?;
+
+  let payload = response.payload.first().ok_or(MercuryError)?;
+  let track = TrackMessage::parse_from_bytes(payload).map_err(|_| MercuryError)?;
+
+  Ok(track.get_explicit())
+}
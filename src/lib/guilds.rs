@@ -0,0 +1,103 @@
+use crate::lib::now_playing::NowPlaying;
+
+use librespot::core::spotify_id::SpotifyId;
+use serenity::{model::id, prelude::TypeMapKey};
+use tokio::sync::Mutex;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Runtime state of one guild. Persistent settings live in `Settings`.
+#[derive(Default)]
+pub struct GuildState {
+  /// Linked user whose player drives the audio in this guild.
+  pub caster: Option<id::UserId>,
+  pub now_playing: Option<NowPlaying>,
+  /// Queued with `!play`, played by the caster's player once the current one ends.
+  pub queue: VecDeque<SpotifyId>,
+}
+
+#[derive(Default)]
+pub struct Guilds {
+  guilds: HashMap<id::GuildId, GuildState>,
+}
+
+impl Guilds {
+  pub fn get(&self, guild_id: id::GuildId) -> Option<&GuildState> {
+    self.guilds.get(&guild_id)
+  }
+
+  pub fn get_mut(&mut self, guild_id: id::GuildId) -> &mut GuildState {
+    self.guilds.entry(guild_id).or_default()
+  }
+
+  pub fn now_playing(&self, guild_id: id::GuildId) -> Option<NowPlaying> {
+    self.get(guild_id)?.now_playing.clone()
+  }
+
+  pub fn all_now_playing(&self) -> Vec<NowPlaying> {
+    self
+      .guilds
+      .values()
+      .filter_map(|state| state.now_playing.clone())
+      .collect()
+  }
+
+  pub fn queue(&self, guild_id: id::GuildId) -> Vec<SpotifyId> {
+    self
+      .get(guild_id)
+      .map(|state| state.queue.iter().copied().collect())
+      .unwrap_or_default()
+  }
+
+  pub fn caster(&self, guild_id: id::GuildId) -> Option<id::UserId> {
+    self.get(guild_id)?.caster
+  }
+
+  /// The guild this user is currently casting to. A user can only be in one voice channel, so
+  /// there is at most one.
+  pub fn cast_by(&self, user_id: id::UserId) -> Option<id::GuildId> {
+    self
+      .guilds
+      .iter()
+      .find(|(_, state)| state.caster == Some(user_id))
+      .map(|(guild_id, _)| *guild_id)
+  }
+
+  /// Makes the user the guild's caster, returning whoever was casting there before.
+  pub fn set_caster(&mut self, guild_id: id::GuildId, user_id: id::UserId) -> Option<id::UserId> {
+    for (other_id, state) in self.guilds.iter_mut() {
+      if *other_id != guild_id && state.caster == Some(user_id) {
+        state.caster = None;
+        state.now_playing = None;
+      }
+    }
+
+    let state = self.get_mut(guild_id);
+    let previous = state.caster.replace(user_id);
+
+    if previous != Some(user_id) {
+      state.now_playing = None;
+    }
+
+    previous.filter(|previous| *previous != user_id)
+  }
+
+  /// Nobody is casting anywhere.
+  pub fn is_idle(&self) -> bool {
+    self.guilds.values().all(|state| state.caster.is_none())
+  }
+
+  pub fn clear_caster(&mut self, guild_id: id::GuildId) {
+    let state = self.get_mut(guild_id);
+    state.caster = None;
+    state.now_playing = None;
+    state.queue.clear();
+  }
+}
+
+pub struct GuildsKey;
+
+impl TypeMapKey for GuildsKey {
+  type Value = Arc<Mutex<Guilds>>;
+}
@@ -0,0 +1,464 @@
+use crate::lib::local;
+use crate::lib::now_playing::TrackInfo;
+
+use chrono::{TimeZone, Utc};
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use rusqlite::{params, Connection, Row};
+use serde_derive::Serialize;
+use serenity::{model::id, prelude::TypeMapKey};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Serialize)]
+pub struct Listen {
+  pub track_id: String,
+  /// `track` or `episode`.
+  pub kind: String,
+  pub name: String,
+  pub artists: Vec<String>,
+  pub user_id: Option<u64>,
+  pub guild_id: u64,
+  pub played_at: i64,
+  pub listened_ms: u64,
+}
+
+impl Listen {
+  fn from_row(row: &Row) -> rusqlite::Result<Listen> {
+    let artists: String = row.get(2)?;
+
+    Ok(Listen {
+      track_id: row.get(0)?,
+      name: row.get(1)?,
+      artists: serde_json::from_str(&artists).unwrap_or_default(),
+      user_id: row.get(3)?,
+      guild_id: row.get(4)?,
+      played_at: row.get(5)?,
+      listened_ms: row.get(6)?,
+      kind: row.get(7)?,
+    })
+  }
+
+  pub fn is_local(&self) -> bool {
+    self.kind == "local"
+  }
+
+  /// Local tracks have no Spotify URI, they are written the way snapshots save them.
+  pub fn uri(&self) -> String {
+    if self.is_local() {
+      format!("{}{}", local::ID_PREFIX, self.track_id)
+    } else {
+      format!("spotify:{}:{}", self.kind, self.track_id)
+    }
+  }
+}
+
+/// A track the guild's blocklist or explicit filter kept from playing.
+#[derive(Debug)]
+pub struct FilteredTrack {
+  pub track_id: String,
+  pub name: String,
+  pub reason: String,
+  pub filtered_at: i64,
+}
+
+/// The listen being recorded in a guild, so resuming after a pause extends it instead of adding a new row.
+struct CurrentListen {
+  row_id: i64,
+  track_id: SpotifyId,
+  listened: Duration,
+  playing_since: Option<Instant>,
+}
+
+impl CurrentListen {
+  fn listened_ms(&self) -> u64 {
+    let playing = self
+      .playing_since
+      .map_or(Duration::ZERO, |since| since.elapsed());
+
+    (self.listened + playing).as_millis() as u64
+  }
+}
+
+pub struct History {
+  conn: Connection,
+  current: HashMap<id::GuildId, CurrentListen>,
+}
+
+impl History {
+  pub fn open(path: &str) -> rusqlite::Result<History> {
+    let conn = Connection::open(path)?;
+
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        track_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        artists TEXT NOT NULL,
+        user_id INTEGER,
+        guild_id INTEGER NOT NULL,
+        played_at INTEGER NOT NULL,
+        listened_ms INTEGER NOT NULL DEFAULT 0
+      );
+      CREATE INDEX IF NOT EXISTS history_guild_played_at ON history (guild_id, played_at);
+      CREATE TABLE IF NOT EXISTS episode_positions (
+        episode_id TEXT PRIMARY KEY,
+        position_ms INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+      );
+      CREATE TABLE IF NOT EXISTS filtered (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        track_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        guild_id INTEGER NOT NULL,
+        reason TEXT NOT NULL,
+        filtered_at INTEGER NOT NULL
+      );",
+    )?;
+
+    // Databases from before episodes were supported only hold tracks
+    if conn.prepare("SELECT kind FROM history LIMIT 0").is_err() {
+      conn.execute(
+        "ALTER TABLE history ADD COLUMN kind TEXT NOT NULL DEFAULT 'track'",
+        [],
+      )?;
+    }
+
+    Ok(History {
+      conn,
+      current: HashMap::new(),
+    })
+  }
+
+  pub fn connection(&self) -> &Connection {
+    &self.conn
+  }
+
+  pub fn is_current(&self, guild_id: id::GuildId, track_id: SpotifyId) -> bool {
+    self
+      .current
+      .get(&guild_id)
+      .map_or(false, |current| current.track_id == track_id)
+  }
+
+  /// Records a new listen, closing the guild's previous one.
+  pub fn start(
+    &mut self,
+    track_id: SpotifyId,
+    info: &TrackInfo,
+    user_id: Option<id::UserId>,
+    guild_id: id::GuildId,
+  ) -> rusqlite::Result<()> {
+    self.finish(guild_id)?;
+
+    self.conn.execute(
+      "INSERT INTO history (track_id, name, artists, user_id, guild_id, played_at, kind)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+      params![
+        track_id.to_base62(),
+        info.name,
+        serde_json::to_string(&info.artists).unwrap(),
+        user_id.map(|user_id| user_id.0),
+        guild_id.0,
+        Utc::now().timestamp(),
+        kind(track_id),
+      ],
+    )?;
+
+    self.current.insert(
+      guild_id,
+      CurrentListen {
+        row_id: self.conn.last_insert_rowid(),
+        track_id,
+        listened: Duration::ZERO,
+        playing_since: Some(Instant::now()),
+      },
+    );
+
+    Ok(())
+  }
+
+  pub fn resume(&mut self, guild_id: id::GuildId) {
+    if let Some(current) = self.current.get_mut(&guild_id) {
+      current.playing_since.get_or_insert_with(Instant::now);
+    }
+  }
+
+  pub fn pause(&mut self, guild_id: id::GuildId) -> rusqlite::Result<()> {
+    if let Some(current) = self.current.get_mut(&guild_id) {
+      if let Some(since) = current.playing_since.take() {
+        current.listened += since.elapsed();
+      }
+    }
+
+    self.save_listened(guild_id)
+  }
+
+  pub fn finish(&mut self, guild_id: id::GuildId) -> rusqlite::Result<()> {
+    self.save_listened(guild_id)?;
+    self.current.remove(&guild_id);
+
+    Ok(())
+  }
+
+  fn save_listened(&self, guild_id: id::GuildId) -> rusqlite::Result<()> {
+    if let Some(current) = self.current.get(&guild_id) {
+      self.conn.execute(
+        "UPDATE history SET listened_ms = ?1 WHERE id = ?2",
+        params![current.listened_ms(), current.row_id],
+      )?;
+    }
+
+    Ok(())
+  }
+
+  /// Most recent listens first.
+  pub fn page(
+    &self,
+    guild_id: id::GuildId,
+    page: usize,
+    per_page: usize,
+  ) -> rusqlite::Result<Vec<Listen>> {
+    let mut statement = self.conn.prepare(
+      "SELECT track_id, name, artists, user_id, guild_id, played_at, listened_ms, kind
+        FROM history WHERE guild_id = ?1 ORDER BY played_at DESC, id DESC LIMIT ?2 OFFSET ?3",
+    )?;
+
+    let listens = statement
+      .query_map(
+        params![guild_id.0, per_page as i64, (page * per_page) as i64],
+        Listen::from_row,
+      )?
+      .collect();
+
+    listens
+  }
+
+  pub fn count(&self, guild_id: id::GuildId) -> rusqlite::Result<usize> {
+    self.conn.query_row(
+      "SELECT COUNT(*) FROM history WHERE guild_id = ?1",
+      params![guild_id.0],
+      |row| row.get::<_, i64>(0).map(|count| count as usize),
+    )
+  }
+
+  /// Oldest listens first, for exports.
+  pub fn all(&self, guild_id: id::GuildId) -> rusqlite::Result<Vec<Listen>> {
+    let mut statement = self.conn.prepare(
+      "SELECT track_id, name, artists, user_id, guild_id, played_at, listened_ms, kind
+        FROM history WHERE guild_id = ?1 ORDER BY played_at, id",
+    )?;
+
+    let listens = statement
+      .query_map(params![guild_id.0], Listen::from_row)?
+      .collect();
+
+    listens
+  }
+
+  /// The guild's most recently played track, episodes aside.
+  pub fn last_track(&self, guild_id: id::GuildId) -> rusqlite::Result<Option<SpotifyId>> {
+    let mut statement = self.conn.prepare(
+      "SELECT track_id FROM history WHERE guild_id = ?1 AND kind = 'track'
+        ORDER BY played_at DESC, id DESC LIMIT 1",
+    )?;
+    let mut rows = statement.query(params![guild_id.0])?;
+
+    match rows.next()? {
+      Some(row) => Ok(SpotifyId::from_base62(&row.get::<_, String>(0)?).ok()),
+      None => Ok(None),
+    }
+  }
+
+  /// Tracks played in the guild since the timestamp.
+  pub fn played_since(
+    &self,
+    guild_id: id::GuildId,
+    since: i64,
+  ) -> rusqlite::Result<HashSet<SpotifyId>> {
+    let mut statement = self.conn.prepare(
+      "SELECT DISTINCT track_id FROM history
+        WHERE guild_id = ?1 AND kind = 'track' AND played_at >= ?2",
+    )?;
+
+    let track_ids = statement
+      .query_map(params![guild_id.0, since], |row| row.get::<_, String>(0))?
+      .filter_map(|track_id| SpotifyId::from_base62(&track_id.ok()?).ok())
+      .collect();
+
+    Ok(track_ids)
+  }
+
+  pub fn log_filtered(
+    &self,
+    guild_id: id::GuildId,
+    track_id: SpotifyId,
+    name: &str,
+    reason: &str,
+  ) -> rusqlite::Result<()> {
+    self.conn.execute(
+      "INSERT INTO filtered (track_id, name, guild_id, reason, filtered_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![
+        track_id.to_base62(),
+        name,
+        guild_id.0,
+        reason,
+        Utc::now().timestamp()
+      ],
+    )?;
+
+    Ok(())
+  }
+
+  /// Most recently filtered first.
+  pub fn filtered(
+    &self,
+    guild_id: id::GuildId,
+    limit: usize,
+  ) -> rusqlite::Result<Vec<FilteredTrack>> {
+    let mut statement = self.conn.prepare(
+      "SELECT track_id, name, reason, filtered_at FROM filtered
+        WHERE guild_id = ?1 ORDER BY filtered_at DESC, id DESC LIMIT ?2",
+    )?;
+
+    let filtered = statement
+      .query_map(params![guild_id.0, limit as i64], |row| {
+        Ok(FilteredTrack {
+          track_id: row.get(0)?,
+          name: row.get(1)?,
+          reason: row.get(2)?,
+          filtered_at: row.get(3)?,
+        })
+      })?
+      .collect();
+
+    filtered
+  }
+
+  /// Where to pick the episode back up, if it was left unfinished.
+  pub fn position(&self, episode_id: SpotifyId) -> rusqlite::Result<Option<u32>> {
+    let mut statement = self
+      .conn
+      .prepare("SELECT position_ms FROM episode_positions WHERE episode_id = ?1")?;
+    let mut rows = statement.query(params![episode_id.to_base62()])?;
+
+    match rows.next()? {
+      Some(row) => row.get(0).map(Some),
+      None => Ok(None),
+    }
+  }
+
+  pub fn save_position(&self, episode_id: SpotifyId, position_ms: u32) -> rusqlite::Result<()> {
+    self.conn.execute(
+      "INSERT INTO episode_positions (episode_id, position_ms, updated_at) VALUES (?1, ?2, ?3)
+        ON CONFLICT (episode_id) DO UPDATE SET position_ms = ?2, updated_at = ?3",
+      params![episode_id.to_base62(), position_ms, Utc::now().timestamp()],
+    )?;
+
+    Ok(())
+  }
+
+  /// Forgets the position of a finished episode, so it starts over next time.
+  pub fn clear_position(&self, episode_id: SpotifyId) -> rusqlite::Result<()> {
+    self.conn.execute(
+      "DELETE FROM episode_positions WHERE episode_id = ?1",
+      params![episode_id.to_base62()],
+    )?;
+
+    Ok(())
+  }
+}
+
+fn kind(id: SpotifyId) -> &'static str {
+  match id.audio_type {
+    SpotifyAudioType::Podcast => "episode",
+    SpotifyAudioType::NonPlayable => "local",
+    _ => "track",
+  }
+}
+
+pub enum ExportFormat {
+  Csv,
+  Json,
+  M3u,
+}
+
+impl ExportFormat {
+  pub fn parse(s: &str) -> Option<ExportFormat> {
+    match s.to_lowercase().as_str() {
+      "csv" => Some(ExportFormat::Csv),
+      "json" => Some(ExportFormat::Json),
+      "m3u" => Some(ExportFormat::M3u),
+      _ => None,
+    }
+  }
+
+  pub fn file_name(&self) -> &'static str {
+    match self {
+      ExportFormat::Csv => "history.csv",
+      ExportFormat::Json => "history.json",
+      ExportFormat::M3u => "history.m3u",
+    }
+  }
+
+  pub fn export(&self, listens: &[Listen]) -> String {
+    match self {
+      ExportFormat::Csv => {
+        let mut csv = String::from("played_at,uri,name,artists,user_id,guild_id,listened_ms\n");
+
+        for listen in listens {
+          csv.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            Utc
+              .timestamp_opt(listen.played_at, 0)
+              .single()
+              .map(|at| at.to_rfc3339())
+              .unwrap_or_default(),
+            listen.uri(),
+            csv_field(&listen.name),
+            csv_field(&listen.artists.join(", ")),
+            listen.user_id.map(|id| id.to_string()).unwrap_or_default(),
+            listen.guild_id,
+            listen.listened_ms
+          ));
+        }
+
+        csv
+      }
+
+      ExportFormat::Json => serde_json::to_string_pretty(listens).unwrap(),
+
+      ExportFormat::M3u => {
+        let mut m3u = String::from("#EXTM3U\n");
+
+        // Nothing else could play a local track's id
+        for listen in listens.iter().filter(|listen| !listen.is_local()) {
+          m3u.push_str(&format!(
+            "#EXTINF:-1,{} - {}\n{}\n",
+            listen.artists.join(", "),
+            listen.name,
+            listen.uri()
+          ));
+        }
+
+        m3u
+      }
+    }
+  }
+}
+
+fn csv_field(value: &str) -> String {
+  if value.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value.to_string()
+  }
+}
+
+pub struct HistoryKey;
+
+impl TypeMapKey for HistoryKey {
+  type Value = Arc<tokio::sync::Mutex<History>>;
+}
@@ -0,0 +1,448 @@
+use crate::lib::events::{Event, Source};
+use crate::lib::now_playing::TrackInfo;
+use crate::lib::player::EmittedSink;
+
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use librespot::playback::{audio_backend::Sink, convert::Converter, decoder::AudioPacket};
+use log::*;
+use rubato::{FftFixedInOut, Resampler};
+use serenity::prelude::TypeMapKey;
+use symphonia::core::{
+  audio::SampleBuffer,
+  codecs::{DecoderOptions, CODEC_TYPE_NULL},
+  errors::Error as SymphoniaError,
+  formats::{FormatOptions, FormatReader},
+  io::MediaSourceStream,
+  meta::{MetadataOptions, MetadataRevision, StandardTagKey},
+  probe::Hint,
+};
+use tokio::sync::broadcast;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+
+const EXTENSIONS: [&str; 3] = ["flac", "mp3", "ogg"];
+
+/// Frames per resampling step, for files not at the rate `EmittedSink` expects.
+const CHUNK_SIZE: usize = 1024;
+
+/// Local tracks are told apart from Spotify ones by their audio type, which Spotify only uses for
+/// things that cannot be played at all.
+pub fn is_local(id: SpotifyId) -> bool {
+  id.audio_type == SpotifyAudioType::NonPlayable
+}
+
+/// Prefix of local ids where they are saved as text, they have no Spotify URI.
+pub const ID_PREFIX: &str = "local:";
+
+/// Ids are derived from the path within the library, so they stay the same across scans and can be
+/// kept in the queue, history and snapshots like Spotify ids. The path is hashed with 128-bit
+/// FNV-1a, which unlike std's hashers is fixed and stays the same across Rust releases.
+fn local_id(path: &Path) -> SpotifyId {
+  const OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+  const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+  let id = path
+    .to_string_lossy()
+    .bytes()
+    .fold(OFFSET_BASIS, |hash, byte| {
+      (hash ^ byte as u128).wrapping_mul(PRIME)
+    });
+
+  SpotifyId {
+    id,
+    audio_type: SpotifyAudioType::NonPlayable,
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalTrack {
+  pub path: PathBuf,
+  pub title: String,
+  pub artists: Vec<String>,
+  pub album: Option<String>,
+  pub duration_ms: u32,
+}
+
+impl LocalTrack {
+  /// Reads the file's tags, falling back to its name for the title.
+  fn read(path: PathBuf) -> Result<LocalTrack, SymphoniaError> {
+    let mut probed = open(&path)?;
+
+    let mut tags = Tags::default();
+    // Vorbis comments live in the container, ID3 tags in front of it
+    if let Some(revision) = probed.format.metadata().current() {
+      tags.read(revision);
+    } else if let Some(metadata) = probed.metadata.get() {
+      if let Some(revision) = metadata.current() {
+        tags.read(revision);
+      }
+    }
+
+    let duration_ms = probed
+      .format
+      .default_track()
+      .and_then(|track| {
+        Some((
+          track.codec_params.n_frames?,
+          track.codec_params.sample_rate?,
+        ))
+      })
+      .map(|(frames, rate)| (frames * 1000 / rate as u64) as u32)
+      .unwrap_or(0);
+
+    let title = tags.title.unwrap_or_else(|| {
+      path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+    });
+
+    Ok(LocalTrack {
+      path,
+      title,
+      artists: tags.artists,
+      album: tags.album,
+      duration_ms,
+    })
+  }
+
+  pub fn info(&self) -> TrackInfo {
+    TrackInfo {
+      name: self.title.clone(),
+      artists: self.artists.clone(),
+      album: self.album.clone().unwrap_or_default(),
+      cover_url: None,
+      duration_ms: self.duration_ms,
+    }
+  }
+
+  fn matches(&self, words: &[String]) -> bool {
+    let haystack = format!(
+      "{} {} {}",
+      self.artists.join(" "),
+      self.title,
+      self.album.as_deref().unwrap_or("")
+    )
+    .to_lowercase();
+
+    words.iter().all(|word| haystack.contains(word.as_str()))
+  }
+}
+
+#[derive(Default)]
+struct Tags {
+  title: Option<String>,
+  artists: Vec<String>,
+  album: Option<String>,
+}
+
+impl Tags {
+  fn read(&mut self, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+      match tag.std_key {
+        Some(StandardTagKey::TrackTitle) => self.title = Some(tag.value.to_string()),
+        Some(StandardTagKey::Artist) => self.artists.push(tag.value.to_string()),
+        Some(StandardTagKey::Album) => self.album = Some(tag.value.to_string()),
+        _ => {}
+      }
+    }
+  }
+}
+
+fn open(path: &Path) -> Result<symphonia::core::probe::ProbeResult, SymphoniaError> {
+  let file = File::open(path)?;
+  let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+  let mut hint = Hint::new();
+  if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+    hint.with_extension(extension);
+  }
+
+  symphonia::default::get_probe().format(
+    &hint,
+    stream,
+    &FormatOptions::default(),
+    &MetadataOptions::default(),
+  )
+}
+
+/// Audio files found under `LOCAL_MUSIC_DIR`, scanned once at startup.
+#[derive(Default)]
+pub struct LocalLibrary {
+  /// Sorted by path, so searches pick the same track every time.
+  tracks: Vec<(SpotifyId, LocalTrack)>,
+}
+
+impl LocalLibrary {
+  pub fn scan(dir: &Path) -> LocalLibrary {
+    let mut paths = Vec::new();
+    if let Err(why) = find_files(dir, &mut paths) {
+      warn!("Could not scan {}: {:?}", dir.display(), why);
+    }
+    paths.sort();
+
+    let mut tracks = Vec::with_capacity(paths.len());
+    for path in paths {
+      let id = local_id(path.strip_prefix(dir).unwrap_or(&path));
+
+      match LocalTrack::read(path.clone()) {
+        Ok(track) => tracks.push((id, track)),
+        Err(why) => warn!("Skipping {}: {:?}", path.display(), why),
+      }
+    }
+
+    info!("Found {} local tracks in {}", tracks.len(), dir.display());
+
+    LocalLibrary { tracks }
+  }
+
+  pub fn get(&self, id: SpotifyId) -> Option<&LocalTrack> {
+    self
+      .tracks
+      .iter()
+      .find(|(track_id, _)| *track_id == id)
+      .map(|(_, track)| track)
+  }
+
+  /// The first track whose artists, title and album contain every word of the query.
+  pub fn search(&self, query: &str) -> Option<SpotifyId> {
+    let words = query
+      .split_whitespace()
+      .map(str::to_lowercase)
+      .collect::<Vec<_>>();
+
+    if words.is_empty() {
+      return None;
+    }
+
+    self
+      .tracks
+      .iter()
+      .find(|(_, track)| track.matches(&words))
+      .map(|(id, _)| *id)
+  }
+}
+
+fn find_files(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+
+    if path.is_dir() {
+      find_files(&path, paths)?;
+      continue;
+    }
+
+    let supported = path
+      .extension()
+      .and_then(|extension| extension.to_str())
+      .map_or(false, |extension| {
+        EXTENSIONS.contains(&extension.to_lowercase().as_str())
+      });
+
+    if supported {
+      paths.push(path);
+    }
+  }
+
+  Ok(())
+}
+
+/// Turns decoded audio into the stereo packets at librespot's sample rate that `EmittedSink`
+/// takes, so local files go through the same resampling as Spotify's.
+struct Converted {
+  resampler: Option<FftFixedInOut<f64>>,
+  input: (Vec<f64>, Vec<f64>),
+}
+
+impl Converted {
+  fn new(sample_rate: u32) -> Converted {
+    let resampler = (sample_rate != librespot::playback::SAMPLE_RATE).then(|| {
+      FftFixedInOut::<f64>::new(
+        sample_rate as usize,
+        librespot::playback::SAMPLE_RATE as usize,
+        CHUNK_SIZE,
+        2,
+      )
+    });
+
+    Converted {
+      resampler,
+      input: (Vec::new(), Vec::new()),
+    }
+  }
+
+  /// Mono is played on both sides, anything beyond two channels is dropped.
+  fn packet(&mut self, samples: &[f32], channels: usize) -> Option<AudioPacket> {
+    for frame in samples.chunks_exact(channels) {
+      let left = frame[0] as f64;
+      let right = frame.get(1).map_or(left, |right| *right as f64);
+      self.input.0.push(left);
+      self.input.1.push(right);
+    }
+
+    let resampler = match self.resampler.as_mut() {
+      Some(resampler) => resampler,
+      None => {
+        let (left, right) = (self.input.0.drain(..), self.input.1.drain(..));
+        let samples = left.zip(right).flat_map(|(l, r)| [l, r]).collect();
+        return Some(AudioPacket::Samples(samples));
+      }
+    };
+
+    let needed = resampler.nbr_frames_needed();
+    let mut samples = Vec::new();
+
+    while self.input.0.len() >= needed {
+      let resampled = resampler
+        .process(&[&self.input.0[..needed], &self.input.1[..needed]])
+        .ok()?;
+      self.input.0.drain(..needed);
+      self.input.1.drain(..needed);
+
+      for i in 0..resampled[0].len() {
+        samples.push(resampled[0][i]);
+        samples.push(resampled[1][i]);
+      }
+    }
+
+    (!samples.is_empty()).then(|| AudioPacket::Samples(samples))
+  }
+}
+
+/// A local file playing into a player's sink. Events are sent as if the player's direct player
+/// played it: `Playing` once it starts and `EndOfTrack` once it ends by itself.
+pub struct LocalPlayback {
+  stop: Arc<AtomicBool>,
+}
+
+impl LocalPlayback {
+  pub fn start(
+    track_id: SpotifyId,
+    track: LocalTrack,
+    sink: EmittedSink,
+    events: Option<broadcast::Sender<(Source, Event)>>,
+  ) -> LocalPlayback {
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let stop_flag = stop.clone();
+    tokio::task::spawn_blocking(move || {
+      let send = |event| {
+        if let Some(events) = &events {
+          let _ = events.send((Source::Direct, event));
+        }
+      };
+
+      match play_file(track_id, &track, sink, &stop_flag, &send) {
+        // Unless it was stopped right as it ended
+        Ok(true) if !stop_flag.swap(true, Ordering::AcqRel) => send(Event::EndOfTrack),
+        Ok(_) => {}
+        Err(why) => {
+          warn!("Could not play {}: {:?}", track.path.display(), why);
+          send(Event::Unavailable { track_id });
+        }
+      }
+    });
+
+    LocalPlayback { stop }
+  }
+
+  /// Returns whether it was still playing.
+  pub fn stop(&self) -> bool {
+    !self.stop.swap(true, Ordering::AcqRel)
+  }
+}
+
+/// Returns whether the file played to its end, rather than being stopped.
+fn play_file(
+  track_id: SpotifyId,
+  track: &LocalTrack,
+  mut sink: EmittedSink,
+  stop: &AtomicBool,
+  send: &dyn Fn(Event),
+) -> Result<bool, SymphoniaError> {
+  let mut format = open(&track.path)?.format;
+
+  let (stream_id, codec_params) = format
+    .tracks()
+    .iter()
+    .find(|stream| stream.codec_params.codec != CODEC_TYPE_NULL)
+    .map(|stream| (stream.id, stream.codec_params.clone()))
+    .ok_or(SymphoniaError::Unsupported("no audio track"))?;
+
+  let mut decoder =
+    symphonia::default::get_codecs().make(&codec_params, &DecoderOptions::default())?;
+  let mut converted = None;
+  let mut converter = Converter::new(None);
+
+  send(Event::Playing {
+    track_id,
+    position_ms: 0,
+    duration_ms: track.duration_ms,
+  });
+
+  while !stop.load(Ordering::Acquire) {
+    let packet = match format.next_packet() {
+      Ok(packet) => packet,
+      Err(SymphoniaError::IoError(why)) if why.kind() == io::ErrorKind::UnexpectedEof => {
+        return Ok(true)
+      }
+      Err(why) => return Err(why),
+    };
+
+    if packet.track_id() != stream_id {
+      continue;
+    }
+
+    let decoded = match decoder.decode(&packet) {
+      Ok(decoded) => decoded,
+      // A corrupt packet is skipped, not the whole file
+      Err(SymphoniaError::DecodeError(why)) => {
+        debug!("Skipping packet of {}: {}", track.title, why);
+        continue;
+      }
+      Err(why) => return Err(why),
+    };
+
+    let spec = *decoded.spec();
+    let mut samples = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+    samples.copy_interleaved_ref(decoded);
+
+    let converted = converted.get_or_insert_with(|| Converted::new(spec.rate));
+    if let Some(packet) = converted.packet(samples.samples(), spec.channels.count()) {
+      sink
+        .write(&packet, &mut converter)
+        .map_err(|why| io::Error::new(io::ErrorKind::Other, format!("{:?}", why)))?;
+    }
+  }
+
+  Ok(false)
+}
+
+pub struct LocalLibraryKey;
+
+impl TypeMapKey for LocalLibraryKey {
+  type Value = Arc<LocalLibrary>;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{is_local, local_id};
+
+  use std::path::Path;
+
+  #[test]
+  fn local_id_is_stable() {
+    let id = local_id(Path::new("Artist/Album/01 Song.flac"));
+
+    // Saved ids must keep pointing at the same file after an upgrade
+    assert_eq!(id.id, 0x348007daf53ba7f8c3cbb8f57a2de21f);
+    assert!(is_local(id));
+    assert_ne!(id, local_id(Path::new("Artist/Album/02 Song.flac")));
+  }
+}
@@ -0,0 +1,103 @@
+use anyhow::{anyhow, bail};
+use librespot::core::{
+  authentication::Credentials, cache::Cache, config::SessionConfig, session::Session,
+};
+use librespot::protocol::authentication::AuthenticationType;
+use log::*;
+
+use std::env;
+use std::io::{self, Write};
+
+/// Picks credentials for the session, preferring the reusable blob cached by a previous login
+/// over an access token, and either over a plaintext password.
+pub fn credentials(cache: Option<&Cache>) -> anyhow::Result<Credentials> {
+  if let Some(credentials) = cache.and_then(|cache| cache.credentials()) {
+    debug!("Using cached credentials for {}", credentials.username);
+
+    if env::var("SPOTIFY_PASSWORD").is_ok() {
+      warn!("Credentials are cached, SPOTIFY_PASSWORD can be removed from the environment");
+    }
+
+    return Ok(credentials);
+  }
+
+  let username = env::var("SPOTIFY_USERNAME").unwrap_or_default();
+
+  if let Ok(token) = env::var("SPOTIFY_ACCESS_TOKEN") {
+    debug!("Using access token");
+
+    return Ok(token_credentials(username, token));
+  }
+
+  if let Ok(password) = env::var("SPOTIFY_PASSWORD") {
+    if username.is_empty() {
+      bail!("Expected a Spotify username in the environment");
+    }
+
+    return Ok(Credentials::with_password(username, password));
+  }
+
+  Err(anyhow!(
+    "No Spotify credentials found, run `login` once or set SPOTIFY_ACCESS_TOKEN"
+  ))
+}
+
+pub fn token_credentials(username: String, token: String) -> Credentials {
+  Credentials {
+    username,
+    auth_type: AuthenticationType::AUTHENTICATION_SPOTIFY_TOKEN,
+    auth_data: token.into_bytes(),
+  }
+}
+
+/// Forgets the password once the session is up. The session has cached reusable credentials
+/// by then, if a cache is configured.
+pub fn drop_password() {
+  env::remove_var("SPOTIFY_PASSWORD");
+}
+
+/// Logs in once with a username and password, so librespot caches reusable credentials and the
+/// password never has to be stored.
+pub async fn login(cache: Option<Cache>) -> anyhow::Result<()> {
+  // A cache opens fine without anywhere to put credentials, check where they would go instead
+  let cache = match cache.filter(|cache| cache.credentials_location().is_some()) {
+    Some(cache) => cache,
+    None => bail!("CACHE_DIR must be set to store credentials"),
+  };
+
+  let username = match env::var("SPOTIFY_USERNAME") {
+    Ok(username) => username,
+    Err(_) => {
+      print!("Spotify username: ");
+      io::stdout().flush()?;
+
+      let mut username = String::new();
+      io::stdin().read_line(&mut username)?;
+      username.trim().to_string()
+    }
+  };
+
+  let password = match env::var("SPOTIFY_PASSWORD") {
+    Ok(password) => password,
+    Err(_) => rpassword::prompt_password_stdout("Spotify password: ")?,
+  };
+
+  let session = Session::connect(
+    SessionConfig::default(),
+    Credentials::with_password(username, password),
+    Some(cache.clone()),
+  )
+  .await
+  .map_err(|why| anyhow!("Could not log in: {:?}", why))?;
+
+  if cache.credentials().is_none() {
+    bail!("Logged in, but could not store credentials in CACHE_DIR");
+  }
+
+  println!(
+    "Logged in as {}, credentials are cached and SPOTIFY_PASSWORD is no longer needed.",
+    session.username()
+  );
+
+  Ok(())
+}
@@ -0,0 +1,249 @@
+use crate::lib::local::{self as local, LocalLibrary, LocalTrack};
+
+use librespot::core::{
+  mercury::MercuryError,
+  session::Session,
+  spotify_id::{SpotifyAudioType, SpotifyId},
+};
+use librespot::metadata::{Album, Artist, Episode, Metadata, Show, Track};
+
+use serenity::{builder::CreateEmbed, model::id};
+
+use std::time::Instant;
+
+#[derive(Clone)]
+pub struct NowPlaying {
+  pub track_id: SpotifyId,
+  pub guild_id: id::GuildId,
+  pub position_ms: u32,
+  pub duration_ms: u32,
+  pub paused: bool,
+  pub updated_at: Instant,
+  pub requested_by: Option<id::UserId>,
+}
+
+impl NowPlaying {
+  pub fn new(
+    track_id: SpotifyId,
+    guild_id: id::GuildId,
+    position_ms: u32,
+    duration_ms: u32,
+    requested_by: Option<id::UserId>,
+  ) -> NowPlaying {
+    NowPlaying {
+      track_id,
+      guild_id,
+      position_ms,
+      duration_ms,
+      paused: false,
+      updated_at: Instant::now(),
+      requested_by,
+    }
+  }
+
+  /// Last reported position plus the time elapsed since, unless paused.
+  pub fn position_ms(&self) -> u32 {
+    if self.paused {
+      return self.position_ms;
+    }
+
+    let elapsed = self.updated_at.elapsed().as_millis() as u32;
+
+    self
+      .position_ms
+      .saturating_add(elapsed)
+      .min(self.duration_ms)
+  }
+
+  pub fn pause(&mut self, position_ms: u32) {
+    self.position_ms = position_ms;
+    self.paused = true;
+    self.updated_at = Instant::now();
+  }
+}
+
+pub struct TrackInfo {
+  pub name: String,
+  pub artists: Vec<String>,
+  pub album: String,
+  pub cover_url: Option<String>,
+  pub duration_ms: u32,
+}
+
+impl TrackInfo {
+  /// Local tracks come from the library, everything else from Spotify.
+  pub async fn lookup(
+    session: &Session,
+    library: &LocalLibrary,
+    track_id: SpotifyId,
+  ) -> Result<TrackInfo, MercuryError> {
+    if local::is_local(track_id) {
+      return library
+        .get(track_id)
+        .map(LocalTrack::info)
+        .ok_or(MercuryError);
+    }
+
+    TrackInfo::fetch(session, track_id).await
+  }
+
+  /// Episodes have their publisher as the artist and their show as the album.
+  pub async fn fetch(session: &Session, track_id: SpotifyId) -> Result<TrackInfo, MercuryError> {
+    if track_id.audio_type == SpotifyAudioType::Podcast {
+      return TrackInfo::fetch_episode(session, track_id).await;
+    }
+
+    let track = Track::get(session, track_id).await?;
+    let album = Album::get(session, track.album).await?;
+
+    let mut artists = Vec::with_capacity(track.artists.len());
+    for artist_id in track.artists {
+      let artist = Artist::get(session, artist_id).await?;
+      artists.push(artist.name);
+    }
+
+    let cover_url = album
+      .covers
+      .first()
+      .map(|file_id| format!("https://i.scdn.co/image/{}", file_id.to_base16()));
+
+    Ok(TrackInfo {
+      name: track.name,
+      artists,
+      album: album.name,
+      cover_url,
+      duration_ms: track.duration as u32,
+    })
+  }
+
+  async fn fetch_episode(
+    session: &Session,
+    episode_id: SpotifyId,
+  ) -> Result<TrackInfo, MercuryError> {
+    let episode = Episode::get(session, episode_id).await?;
+    let show = Show::get(session, episode.show).await?;
+
+    let cover_url = episode
+      .covers
+      .first()
+      .or_else(|| show.covers.first())
+      .map(|file_id| format!("https://i.scdn.co/image/{}", file_id.to_base16()));
+
+    Ok(TrackInfo {
+      name: episode.name,
+      artists: vec![show.publisher],
+      album: show.name,
+      cover_url,
+      duration_ms: episode.duration as u32,
+    })
+  }
+}
+
+/// Formats ids to save in snapshots. Local tracks get their own prefix, their URI would read back
+/// as a Spotify track.
+pub fn format_id(id: SpotifyId) -> String {
+  if local::is_local(id) {
+    format!("{}{}", local::ID_PREFIX, id.to_base62())
+  } else {
+    id.to_uri()
+  }
+}
+
+/// Parses ids as saved in snapshots. URIs tell tracks and episodes apart, bare ids are tracks.
+pub fn parse_id(id: &str) -> Option<SpotifyId> {
+  if let Some(local_id) = id.strip_prefix(local::ID_PREFIX) {
+    let local_id = SpotifyId::from_base62(local_id).ok()?;
+
+    Some(SpotifyId {
+      audio_type: SpotifyAudioType::NonPlayable,
+      ..local_id
+    })
+  } else if id.starts_with("spotify:") {
+    SpotifyId::from_uri(id).ok()
+  } else {
+    SpotifyId::from_base62(id).ok()
+  }
+}
+
+/// Formats milliseconds as `m:ss`.
+pub fn format_duration(ms: u32) -> String {
+  let seconds = ms / 1000;
+
+  format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+pub fn progress_bar(position_ms: u32, duration_ms: u32, width: usize) -> String {
+  let filled = if duration_ms == 0 {
+    0
+  } else {
+    (position_ms as u64 * width as u64 / duration_ms as u64) as usize
+  };
+  let filled = filled.min(width - 1);
+
+  let mut bar = "▬".repeat(filled);
+  bar.push('🔘');
+  bar.push_str(&"▬".repeat(width - 1 - filled));
+
+  bar
+}
+
+pub fn embed<'a>(
+  e: &'a mut CreateEmbed,
+  info: &TrackInfo,
+  now_playing: &NowPlaying,
+) -> &'a mut CreateEmbed {
+  let position_ms = now_playing.position_ms();
+  let state = if now_playing.paused { "⏸" } else { "▶" };
+
+  e.title(&info.name);
+  e.description(format!(
+    "{} {} `{} / {}`",
+    state,
+    progress_bar(position_ms, info.duration_ms, 16),
+    format_duration(position_ms),
+    format_duration(info.duration_ms)
+  ));
+  e.field("artists", info.artists.join(", "), true);
+  e.field("album", &info.album, true);
+
+  if let Some(user_id) = now_playing.requested_by {
+    e.field("requested by", format!("<@{}>", user_id.0), true);
+  }
+
+  if let Some(cover_url) = &info.cover_url {
+    e.thumbnail(cover_url);
+  }
+
+  e
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{format_id, parse_id};
+
+  use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+
+  #[test]
+  fn local_ids_read_back_as_local() {
+    let id = SpotifyId {
+      id: 42,
+      audio_type: SpotifyAudioType::NonPlayable,
+    };
+
+    let formatted = format_id(id);
+    assert!(formatted.starts_with("local:"));
+    assert_eq!(parse_id(&formatted), Some(id));
+  }
+
+  #[test]
+  fn spotify_ids_read_back_as_they_were() {
+    let track = SpotifyId::from_uri("spotify:track:4uLU6hMCjMI75M1A2tKUQC").unwrap();
+    let episode = SpotifyId::from_uri("spotify:episode:4uLU6hMCjMI75M1A2tKUQC").unwrap();
+
+    assert_eq!(format_id(track), "spotify:track:4uLU6hMCjMI75M1A2tKUQC");
+    assert_eq!(parse_id(&format_id(track)), Some(track));
+    assert_eq!(parse_id(&format_id(episode)), Some(episode));
+    // Older snapshots saved bare ids
+    assert_eq!(parse_id("4uLU6hMCjMI75M1A2tKUQC"), Some(track));
+  }
+}
@@ -0,0 +1,419 @@
+use crate::lib::player::EmittedSink;
+use crate::lib::prefetch;
+
+use librespot::audio::AudioDecrypt;
+use librespot::core::{
+  audio_key::AudioKey,
+  authentication::Credentials,
+  cache::Cache,
+  session::Session,
+  spotify_id::{FileId, SpotifyAudioType, SpotifyId},
+};
+use librespot::metadata::{Metadata, Track};
+use librespot::playback::{
+  audio_backend::Sink,
+  config::Bitrate,
+  convert::Converter,
+  decoder::{AudioDecoder, VorbisDecoder},
+};
+use log::*;
+use rusqlite::{params, Connection, OptionalExtension};
+use serenity::model::id;
+use serenity::prelude::TypeMapKey;
+use tokio::sync::Mutex;
+use tokio::time::Duration;
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+
+/// Spotify puts a header in front of the Ogg data of its audio files.
+const HEADER_SIZE: u64 = 0xa7;
+
+/// A cached audio file along with the key to decrypt it.
+#[derive(Debug, Clone)]
+pub struct OfflineTrack {
+  pub track_id: SpotifyId,
+  pub file_id: FileId,
+  pub key: AudioKey,
+  pub name: String,
+}
+
+/// Audio keys of tracks played or prefetched while online. The audio cache is encrypted, so these
+/// are what makes it playable without Spotify.
+pub struct OfflineLibrary {
+  conn: Connection,
+}
+
+impl OfflineLibrary {
+  pub fn open(path: &str) -> rusqlite::Result<OfflineLibrary> {
+    let conn = Connection::open(path)?;
+
+    conn.execute_batch(
+      "CREATE TABLE IF NOT EXISTS audio_keys (
+        file_id BLOB PRIMARY KEY,
+        track_id TEXT NOT NULL,
+        key BLOB NOT NULL,
+        name TEXT NOT NULL
+      );",
+    )?;
+
+    Ok(OfflineLibrary { conn })
+  }
+
+  fn contains(&self, file_id: FileId) -> rusqlite::Result<bool> {
+    self
+      .conn
+      .query_row(
+        "SELECT 1 FROM audio_keys WHERE file_id = ?1",
+        params![&file_id.0[..]],
+        |_| Ok(()),
+      )
+      .optional()
+      .map(|row| row.is_some())
+  }
+
+  fn save(&self, track: &OfflineTrack) -> rusqlite::Result<()> {
+    self.conn.execute(
+      "INSERT OR REPLACE INTO audio_keys (file_id, track_id, key, name) VALUES (?1, ?2, ?3, ?4)",
+      params![
+        &track.file_id.0[..],
+        track.track_id.to_base62(),
+        &track.key.0[..],
+        track.name
+      ],
+    )?;
+
+    Ok(())
+  }
+
+  /// Every track whose audio is still in the cache, shuffled.
+  pub fn mix(&self, cache: &Cache) -> rusqlite::Result<Vec<OfflineTrack>> {
+    let mut stmt = self
+      .conn
+      .prepare("SELECT file_id, track_id, key, name FROM audio_keys ORDER BY RANDOM()")?;
+
+    let rows = stmt.query_map([], |row| {
+      Ok((
+        row.get::<_, Vec<u8>>(0)?,
+        row.get::<_, String>(1)?,
+        row.get::<_, Vec<u8>>(2)?,
+        row.get::<_, String>(3)?,
+      ))
+    })?;
+
+    let mut tracks = Vec::new();
+    for row in rows {
+      let (file_id, track_id, key, name) = row?;
+
+      let (file_id, track_id, key) = match (
+        <[u8; 20]>::try_from(file_id.as_slice()),
+        SpotifyId::from_base62(&track_id),
+        <[u8; 16]>::try_from(key.as_slice()),
+      ) {
+        (Ok(file_id), Ok(track_id), Ok(key)) => (FileId(file_id), track_id, AudioKey(key)),
+        _ => continue,
+      };
+
+      // Evicted from the cache since
+      if cache.file_path(file_id).map_or(false, |path| path.exists()) {
+        tracks.push(OfflineTrack {
+          track_id,
+          file_id,
+          key,
+          name,
+        });
+      }
+    }
+
+    Ok(tracks)
+  }
+}
+
+/// Saves the key of the file the player picks for the track at this bitrate, so it can be played
+/// from the cache later on. Keys already saved are not requested again.
+pub async fn remember(
+  offline: &Mutex<Offline>,
+  session: &Session,
+  track_id: SpotifyId,
+  bitrate: Bitrate,
+) {
+  if track_id.audio_type != SpotifyAudioType::Track {
+    return;
+  }
+
+  let track = match Track::get(session, track_id).await {
+    Ok(track) => track,
+    Err(why) => {
+      debug!("Could not fetch track {}: {:?}", track_id.to_base62(), why);
+      return;
+    }
+  };
+
+  let file_id = match prefetch::pick_file(&track, bitrate) {
+    Some(file_id) => file_id,
+    None => return,
+  };
+
+  if let Ok(true) = offline.lock().await.library.contains(file_id) {
+    return;
+  }
+
+  let key = match session.audio_key().request(track_id, file_id).await {
+    Ok(key) => key,
+    Err(why) => {
+      debug!("Could not fetch audio key for {}: {:?}", track.name, why);
+      return;
+    }
+  };
+
+  let track = OfflineTrack {
+    track_id,
+    file_id,
+    key,
+    name: track.name,
+  };
+
+  if let Err(why) = offline.lock().await.library.save(&track) {
+    warn!("Could not save audio key for {}: {:?}", track.name, why);
+  }
+}
+
+/// Skips the header, so the decoder sees the file as starting at the Ogg data.
+struct Subfile<T> {
+  stream: T,
+  offset: u64,
+}
+
+impl<T: Read + Seek> Subfile<T> {
+  fn new(mut stream: T, offset: u64) -> io::Result<Subfile<T>> {
+    stream.seek(SeekFrom::Start(offset))?;
+    Ok(Subfile { stream, offset })
+  }
+}
+
+impl<T: Read> Read for Subfile<T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.stream.read(buf)
+  }
+}
+
+impl<T: Seek> Seek for Subfile<T> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    let pos = match pos {
+      SeekFrom::Start(offset) => SeekFrom::Start(offset + self.offset),
+      pos => pos,
+    };
+
+    let position = self.stream.seek(pos)?;
+    Ok(position.saturating_sub(self.offset))
+  }
+}
+
+/// Decodes the cached file into the sink, until it ends or the mix is stopped.
+fn play_file(
+  cache: &Cache,
+  track: &OfflineTrack,
+  sink: &mut EmittedSink,
+  converter: &mut Converter,
+  stop: &AtomicBool,
+) -> Result<(), String> {
+  let file = cache.file(track.file_id).ok_or("no longer cached")?;
+  let file =
+    Subfile::new(AudioDecrypt::new(track.key, file), HEADER_SIZE).map_err(|why| why.to_string())?;
+  let mut decoder = VorbisDecoder::new(file).map_err(|why| format!("{:?}", why))?;
+
+  info!("Offline mix playing {}", track.name);
+
+  while !stop.load(Ordering::Acquire) {
+    match decoder.next_packet().map_err(|why| format!("{:?}", why))? {
+      Some(packet) => sink
+        .write(&packet, converter)
+        .map_err(|why| format!("{:?}", why))?,
+      None => break,
+    }
+  }
+
+  Ok(())
+}
+
+/// Plays a shuffled mix of cached tracks into a sink, over and over, until stopped.
+struct Mix {
+  guild_id: id::GuildId,
+  stop: Arc<AtomicBool>,
+  /// Set once the mix noticed it was stopped.
+  done: Arc<AtomicBool>,
+}
+
+impl Mix {
+  fn start(
+    guild_id: id::GuildId,
+    tracks: Vec<OfflineTrack>,
+    cache: Cache,
+    sink: EmittedSink,
+  ) -> Mix {
+    let stop = Arc::new(AtomicBool::new(false));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let (stop_flag, done_flag) = (stop.clone(), done.clone());
+    tokio::task::spawn_blocking(move || {
+      let mut sink = sink;
+      let mut converter = Converter::new(None);
+
+      'mix: loop {
+        let mut played = 0;
+
+        for track in &tracks {
+          if stop_flag.load(Ordering::Acquire) {
+            break 'mix;
+          }
+
+          match play_file(&cache, track, &mut sink, &mut converter, &stop_flag) {
+            Ok(()) => played += 1,
+            Err(why) => warn!("Offline mix could not play {}: {}", track.name, why),
+          }
+        }
+
+        if played == 0 {
+          warn!("Nothing in the offline mix is playable");
+          break;
+        }
+      }
+
+      done_flag.store(true, Ordering::Release);
+    });
+
+    Mix {
+      guild_id,
+      stop,
+      done,
+    }
+  }
+
+  fn stop(self, sink: &EmittedSink) {
+    self.stop.store(true, Ordering::Release);
+
+    // The mix blocks while nobody reads the sink, so keep reading until it noticed. Voice may
+    // still be reading too, in which case it holds the receiver.
+    let receiver = sink.receiver.clone();
+    let done = self.done;
+    tokio::task::spawn_blocking(move || {
+      while !done.load(Ordering::Acquire) {
+        match receiver.try_lock() {
+          Ok(receiver) => {
+            let _ = receiver.recv_timeout(Duration::from_millis(100));
+          }
+          Err(_) => std::thread::sleep(Duration::from_millis(100)),
+        }
+      }
+    });
+  }
+}
+
+/// What a linked user logs in with, kept while their session is down.
+#[derive(Clone)]
+pub struct PendingLogin {
+  pub credentials: Credentials,
+  pub cache: Option<Cache>,
+}
+
+/// Degraded mode for linked users whose session could not connect. While Spotify is unreachable
+/// they get a shuffled mix of what is already in the audio cache.
+pub struct Offline {
+  pub library: OfflineLibrary,
+  pending: HashMap<id::UserId, PendingLogin>,
+  sink: EmittedSink,
+  /// Only one mix plays at a time, there is only one sink.
+  mix: Option<(id::UserId, Mix)>,
+}
+
+impl Offline {
+  pub fn new(library: OfflineLibrary) -> Offline {
+    Offline {
+      library,
+      pending: HashMap::new(),
+      sink: EmittedSink::new(),
+      mix: None,
+    }
+  }
+
+  pub fn add_pending(&mut self, user_id: id::UserId, login: PendingLogin) {
+    self.pending.insert(user_id, login);
+  }
+
+  pub fn is_pending(&self, user_id: id::UserId) -> bool {
+    self.pending.contains_key(&user_id)
+  }
+
+  pub fn pending(&self) -> Vec<(id::UserId, PendingLogin)> {
+    self
+      .pending
+      .iter()
+      .map(|(user_id, login)| (*user_id, login.clone()))
+      .collect()
+  }
+
+  /// The user is back online, stops their mix if it is playing. Returns the guild it played in.
+  pub fn reconnected(&mut self, user_id: id::UserId) -> Option<id::GuildId> {
+    self.pending.remove(&user_id);
+    self.stop_mix(user_id)
+  }
+
+  /// Starts the mix for a pending user, replacing any other mix. Returns the sink to play in
+  /// voice, or `None` if nothing cached can be played.
+  pub fn start_mix(&mut self, user_id: id::UserId, guild_id: id::GuildId) -> Option<EmittedSink> {
+    if !self.is_pending(user_id) {
+      return None;
+    }
+
+    let cache = self.pending.get(&user_id)?.cache.clone()?;
+    let tracks = match self.library.mix(&cache) {
+      Ok(tracks) => tracks,
+      Err(why) => {
+        warn!("Could not load the offline mix: {:?}", why);
+        return None;
+      }
+    };
+
+    if tracks.is_empty() {
+      info!("No cached tracks to play offline for {}", user_id);
+      return None;
+    }
+
+    if let Some((_, mix)) = self.mix.take() {
+      mix.stop(&self.sink);
+    }
+
+    // A fresh sink, so nothing the previous mix left in it plays
+    self.sink = EmittedSink::new();
+
+    info!("Starting offline mix of {} tracks", tracks.len());
+    let mix = Mix::start(guild_id, tracks, cache, self.sink.clone());
+    self.mix = Some((user_id, mix));
+
+    Some(self.sink.clone())
+  }
+
+  /// Stops the user's mix, if it is playing. Returns the guild it played in.
+  pub fn stop_mix(&mut self, user_id: id::UserId) -> Option<id::GuildId> {
+    match &self.mix {
+      Some((mix_user_id, _)) if *mix_user_id == user_id => {}
+      _ => return None,
+    }
+
+    let (_, mix) = self.mix.take()?;
+    let guild_id = mix.guild_id;
+    mix.stop(&self.sink);
+
+    Some(guild_id)
+  }
+}
+
+pub struct OfflineKey;
+
+impl TypeMapKey for OfflineKey {
+  type Value = Arc<Mutex<Offline>>;
+}
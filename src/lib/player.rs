@@ -3,7 +3,8 @@ use librespot::core::{
   authentication::Credentials,
   cache::Cache,
   config::{ConnectConfig, DeviceType, SessionConfig},
-  session::Session,
+  session::{Session, SessionError},
+  spotify_id::SpotifyId,
 };
 use librespot::playback::{
   audio_backend,
@@ -17,25 +18,208 @@ use librespot::playback::{
   player::{Player, PlayerEventChannel},
 };
 
-use serenity::prelude::TypeMapKey;
+use crate::lib::events::{Event, PlayerEvents, Source};
+use crate::lib::local::{LocalPlayback, LocalTrack};
+use crate::lib::session::SessionStatus;
+
+use log::*;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
 
 use std::clone::Clone;
 use std::sync::{
+  atomic::{AtomicBool, Ordering},
   mpsc::{sync_channel, Receiver, SyncSender},
   Arc, Mutex,
 };
-use std::{io, mem};
+use std::{fmt, io, mem};
 
 use byteorder::{ByteOrder, LittleEndian};
 use rubato::{FftFixedInOut, Resampler};
+use serde_derive::{Deserialize, Serialize};
 use songbird::input::reader::MediaSource;
 
+/// Spotify bitrate, fixed or picked from the bitrate of the voice channel it ends up in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Quality {
+  #[serde(rename = "auto")]
+  Auto,
+  #[serde(rename = "96")]
+  Low,
+  #[serde(rename = "160")]
+  Normal,
+  #[serde(rename = "320")]
+  High,
+}
+
+impl Quality {
+  pub fn parse(quality: &str) -> Option<Quality> {
+    match quality {
+      "auto" => Some(Quality::Auto),
+      "96" => Some(Quality::Low),
+      "160" => Some(Quality::Normal),
+      "320" => Some(Quality::High),
+      _ => None,
+    }
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Quality::Auto => "auto",
+      Quality::Low => "96",
+      Quality::Normal => "160",
+      Quality::High => "320",
+    }
+  }
+
+  /// There is no point streaming more than the voice channel passes on.
+  pub fn bitrate(self, channel_bitrate: Option<u64>) -> Bitrate {
+    match self {
+      Quality::Low => Bitrate::Bitrate96,
+      Quality::Normal => Bitrate::Bitrate160,
+      Quality::High => Bitrate::Bitrate320,
+      Quality::Auto => match channel_bitrate {
+        Some(bitrate) if bitrate <= 96_000 => Bitrate::Bitrate96,
+        Some(bitrate) if bitrate <= 160_000 => Bitrate::Bitrate160,
+        _ => Bitrate::Bitrate320,
+      },
+    }
+  }
+}
+
+/// How the bot shows up in the Spotify device picker, and how it plays.
+#[derive(Clone)]
+pub struct DeviceConfig {
+  /// Shared by every player, and by discovery so the Spotify app finds the device it logged in.
+  pub device_id: String,
+  pub name: String,
+  pub device_type: DeviceType,
+  pub initial_volume: Option<u16>,
+  pub volume_ctrl: VolumeCtrl,
+  pub autoplay: bool,
+  pub quality: Quality,
+}
+
+/// Where casting is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectState {
+  /// The device does not show up in the Spotify device picker.
+  Disconnected,
+  /// The device shows up in the picker, nobody is casting to it.
+  ConnectAdvertised,
+  /// Someone is casting to the device.
+  Active,
+  /// Casting was disabled, the previous Spirc task has not finished yet.
+  ShuttingDown,
+}
+
+/// What moves casting from one `ConnectState` to the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectEvent {
+  /// A Spirc was started, the device shows up in the picker.
+  Advertised,
+  /// Someone started casting to the device.
+  Activated,
+  /// Casting to the device stopped, it is still in the picker.
+  Deactivated,
+  /// The Spirc was told to shut down.
+  ShutdownStarted,
+  /// The Spirc task finished after being told to shut down.
+  ShutdownFinished,
+  /// The Spirc task ended on its own, e.g. when the session dropped.
+  TaskExited,
+}
+
+impl ConnectState {
+  /// The state after `event`. Events that do not apply in this state leave it as it is, e.g.
+  /// the previous Spirc going inactive while it shuts down.
+  pub fn on(self, event: ConnectEvent) -> ConnectState {
+    match (self, event) {
+      (_, ConnectEvent::Advertised) => ConnectState::ConnectAdvertised,
+      (ConnectState::ConnectAdvertised, ConnectEvent::Activated) => ConnectState::Active,
+      (ConnectState::Active, ConnectEvent::Deactivated) => ConnectState::ConnectAdvertised,
+      (ConnectState::ConnectAdvertised | ConnectState::Active, ConnectEvent::ShutdownStarted) => {
+        ConnectState::ShuttingDown
+      }
+      (ConnectState::ShuttingDown, ConnectEvent::ShutdownFinished) => ConnectState::Disconnected,
+      (ConnectState::ConnectAdvertised | ConnectState::Active, ConnectEvent::TaskExited) => {
+        ConnectState::Disconnected
+      }
+      (state, _) => state,
+    }
+  }
+}
+
+impl fmt::Display for ConnectState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ConnectState::Disconnected => write!(f, "not casting"),
+      ConnectState::ConnectAdvertised => write!(f, "in device picker"),
+      ConnectState::Active => write!(f, "casting"),
+      ConnectState::ShuttingDown => write!(f, "shutting down"),
+    }
+  }
+}
+
+/// How long enabling casting waits for the previous Spirc task to finish.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What enabling casting has to do about the Spirc already running, if any.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Enable {
+  /// Casting is enabled under the name already.
+  Keep,
+  /// Casting is enabled under another name, it is shut down first.
+  Restart,
+  /// Nothing is advertised, or it is on its way out.
+  Start,
+}
+
+impl Enable {
+  fn plan(state: ConnectState, current: Option<&str>, name: &str) -> Enable {
+    match state {
+      ConnectState::ConnectAdvertised | ConnectState::Active if current == Some(name) => {
+        Enable::Keep
+      }
+      ConnectState::ConnectAdvertised | ConnectState::Active => Enable::Restart,
+      ConnectState::Disconnected | ConnectState::ShuttingDown => Enable::Start,
+    }
+  }
+}
+
+/// Waits up to `limit` for a Spirc task to finish. Returns whether it did.
+async fn wait_for_shutdown(task: JoinHandle<()>, limit: Duration) -> bool {
+  timeout(limit, task).await.is_ok()
+}
+
 pub struct SpotifyPlayer {
+  device: DeviceConfig,
   player_config: PlayerConfig,
+  session_config: SessionConfig,
+  credentials: Credentials,
+  cache: Option<Cache>,
   pub emitted_sink: EmittedSink,
   pub session: Session,
+  pub status: SessionStatus,
+  /// Plays tracks we load ourselves, e.g. when resuming a previous session.
+  player: Player,
+  /// Events of both players, subscribe with `subscribe`.
+  pub events: PlayerEvents,
+  /// Device name casting is enabled under, if it is.
+  connect_name: Option<String>,
+  /// Volume casting starts at, overriding the configured initial volume.
+  pub volume: Option<u16>,
   pub spirc: Option<Box<Spirc>>,
-  pub event_channel: Option<Arc<tokio::sync::Mutex<PlayerEventChannel>>>,
+  connect_state: ConnectState,
+  /// Task driving the current, or shutting down, Spirc.
+  spirc_task: Option<JoinHandle<()>>,
+  /// Set once `spirc_task` finished.
+  spirc_done: Arc<AtomicBool>,
+  /// Local file the direct player is standing in for, if any.
+  local: Option<LocalPlayback>,
+  /// Task reconnecting the session when it drops, there is one per player.
+  watcher: Option<JoinHandle<()>>,
 }
 
 pub struct EmittedSink {
@@ -47,7 +231,7 @@ pub struct EmittedSink {
 }
 
 impl EmittedSink {
-  fn new() -> EmittedSink {
+  pub fn new() -> EmittedSink {
     // By setting the sync_channel bound to at least the output frame size of one resampling
     // step (1120 for a chunk size of 1024 and our frequency settings) the number of
     // synchronisations needed between EmittedSink::write and EmittedSink::read can be reduced.
@@ -75,6 +259,12 @@ impl EmittedSink {
   }
 }
 
+impl Default for EmittedSink {
+  fn default() -> Self {
+    EmittedSink::new()
+  }
+}
+
 impl audio_backend::Sink for EmittedSink {
   fn start(&mut self) -> SinkResult<()> {
     Ok(())
@@ -183,67 +373,223 @@ impl Clone for EmittedSink {
   }
 }
 
-pub struct SpotifyPlayerKey;
+fn direct_player(
+  player_config: &PlayerConfig,
+  session: &Session,
+  emitted_sink: &EmittedSink,
+) -> (Player, PlayerEventChannel) {
+  let cloned_sink = emitted_sink.clone();
 
-impl TypeMapKey for SpotifyPlayerKey {
-  type Value = Arc<tokio::sync::Mutex<SpotifyPlayer>>;
+  Player::new(player_config.clone(), session.clone(), None, move || {
+    Box::new(cloned_sink)
+  })
 }
 
 impl SpotifyPlayer {
-  pub async fn new(
-    username: String,
-    password: String,
-    quality: Bitrate,
-    cache_dir: Option<String>,
-  ) -> SpotifyPlayer {
-    let credentials = Credentials::with_password(username, password);
-
-    let session_config = SessionConfig::default();
-
-    // 4 GB
-    let mut cache_limit: u64 = 10;
-    cache_limit = cache_limit.pow(9);
-    cache_limit *= 4;
+  pub async fn try_new(
+    credentials: Credentials,
+    device: DeviceConfig,
+    cache: Option<Cache>,
+  ) -> Result<SpotifyPlayer, SessionError> {
+    let session_config = SessionConfig {
+      device_id: device.device_id.clone(),
+      ..SessionConfig::default()
+    };
 
-    let cache = Cache::new(cache_dir.clone(), cache_dir, Some(cache_limit)).ok();
+    let session =
+      Session::connect(session_config.clone(), credentials.clone(), cache.clone()).await?;
 
-    let session = Session::connect(session_config, credentials, cache)
-      .await
-      .expect("Error creating session");
+    // Reconnect with the reusable credentials cached on login, rather than a password
+    let credentials = cache
+      .as_ref()
+      .and_then(|cache| cache.credentials())
+      .unwrap_or(credentials);
 
     let player_config = PlayerConfig {
-      bitrate: quality,
+      bitrate: device.quality.bitrate(None),
       ..Default::default()
     };
 
     let emitted_sink = EmittedSink::new();
 
-    let cloned_sink = emitted_sink.clone();
+    let (player, direct_events) = direct_player(&player_config, &session, &emitted_sink);
 
-    let (_player, rx) = Player::new(player_config.clone(), session.clone(), None, move || {
-      Box::new(cloned_sink)
-    });
+    let mut events = PlayerEvents::new();
+    events.forward(Source::Direct, direct_events);
 
-    SpotifyPlayer {
+    Ok(SpotifyPlayer {
+      device,
       player_config,
+      session_config,
+      credentials,
+      cache,
       emitted_sink,
       session,
+      status: SessionStatus::new(),
+      player,
+      events,
+      connect_name: None,
+      volume: None,
       spirc: None,
-      event_channel: Some(Arc::new(tokio::sync::Mutex::new(rx))),
+      connect_state: ConnectState::Disconnected,
+      spirc_task: None,
+      spirc_done: Arc::new(AtomicBool::new(true)),
+      local: None,
+      watcher: None,
+    })
+  }
+
+  /// Keeps `watcher` as the only session watcher, aborting any previous one.
+  pub fn set_watcher(&mut self, watcher: JoinHandle<()>) {
+    if let Some(previous) = self.watcher.replace(watcher) {
+      previous.abort();
+    }
+  }
+
+  /// The watcher holds on to the player, so it must be stopped once the player is let go of.
+  pub fn stop_watcher(&mut self) {
+    if let Some(watcher) = self.watcher.take() {
+      watcher.abort();
+    }
+  }
+
+  pub fn session_params(&self) -> (SessionConfig, Credentials, Option<Cache>) {
+    (
+      self.session_config.clone(),
+      self.credentials.clone(),
+      self.cache.clone(),
+    )
+  }
+
+  /// Account to reconnect with, after someone else logged the device in.
+  pub fn set_credentials(&mut self, credentials: Credentials) {
+    self.credentials = credentials;
+  }
+
+  /// Moves playback onto a freshly connected session.
+  pub async fn replace_session(&mut self, session: Session) {
+    self.session = session;
+    self.rebuild().await;
+  }
+
+  pub fn bitrate(&self) -> Bitrate {
+    self.player_config.bitrate
+  }
+
+  /// Rebuilds the players at the new bitrate. The sink stays the same, so the voice connection
+  /// keeps streaming from it.
+  pub async fn set_bitrate(&mut self, bitrate: Bitrate) {
+    self.player_config.bitrate = bitrate;
+    self.rebuild().await;
+  }
+
+  /// Recreates the players from the current session and config. Their events go onto the same
+  /// bus, so subscribers carry on as before.
+  async fn rebuild(&mut self) {
+    let (player, direct_events) =
+      direct_player(&self.player_config, &self.session, &self.emitted_sink);
+    self.player = player;
+    self.events.forward(Source::Direct, direct_events);
+
+    if let Some(name) = self.connect_name.clone() {
+      self.disable_connect().await;
+      self.enable_connect(name).await;
+    }
+  }
+
+  pub fn load(&mut self, track_id: SpotifyId, position_ms: u32, start_playing: bool) {
+    if let Some(local) = self.local.take() {
+      local.stop();
+    }
+    self.player.load(track_id, start_playing, position_ms);
+  }
+
+  /// Plays a file from the local library into the same sink, in place of the direct player.
+  pub fn load_local(&mut self, track_id: SpotifyId, track: LocalTrack) {
+    self.player.stop();
+    if let Some(local) = self.local.take() {
+      local.stop();
     }
+
+    self.local = Some(LocalPlayback::start(
+      track_id,
+      track,
+      self.emitted_sink.clone(),
+      self.events.sender(),
+    ));
+  }
+
+  pub fn stop_direct(&self) {
+    self.player.stop();
+
+    let stopped = self.local.as_ref().map_or(false, |local| local.stop());
+    if let (true, Some(events)) = (stopped, self.events.sender()) {
+      let _ = events.send((Source::Direct, Event::Stopped));
+    }
+  }
+
+  pub fn device(&self) -> &DeviceConfig {
+    &self.device
+  }
+
+  pub fn subscribe(&self) -> Option<broadcast::Receiver<(Source, Event)>> {
+    self.events.subscribe()
+  }
+
+  pub fn connect_name(&self) -> Option<&str> {
+    self.connect_name.as_deref()
+  }
+
+  pub fn state(&self) -> ConnectState {
+    if !self.spirc_done.load(Ordering::Acquire) {
+      return self.connect_state;
+    }
+
+    // A Spirc we did not shut down is still held on to
+    let event = match self.spirc {
+      Some(_) => ConnectEvent::TaskExited,
+      None => ConnectEvent::ShutdownFinished,
+    };
+    self.connect_state.on(event)
   }
 
-  pub async fn enable_connect(&mut self) {
+  /// Someone started or stopped casting to the device. Ignored unless casting is enabled.
+  pub fn set_active(&mut self, active: bool) {
+    let event = match active {
+      true => ConnectEvent::Activated,
+      false => ConnectEvent::Deactivated,
+    };
+    self.connect_state = self.connect_state.on(event);
+  }
+
+  /// Shows the device in the Spotify device picker under `name`. Does nothing if it already is,
+  /// casting under another name is restarted.
+  pub async fn enable_connect(&mut self, name: String) {
+    match Enable::plan(self.state(), self.connect_name.as_deref(), &name) {
+      Enable::Keep => return,
+      Enable::Restart => self.disable_connect().await,
+      Enable::Start => {}
+    }
+
+    // Two Spirc tasks would both show up in the device picker
+    if let Some(task) = self.spirc_task.take() {
+      if !wait_for_shutdown(task, SHUTDOWN_TIMEOUT).await {
+        warn!("Previous Spirc did not shut down in time");
+      }
+    }
+
+    self.connect_name = Some(name.clone());
+
     let config = ConnectConfig {
-      name: "Discord".to_string(),
-      device_type: DeviceType::AudioDongle,
-      initial_volume: None,
-      has_volume_ctrl: true,
-      autoplay: true,
+      name,
+      device_type: self.device.device_type,
+      initial_volume: self.volume.or(self.device.initial_volume),
+      has_volume_ctrl: !matches!(self.device.volume_ctrl, VolumeCtrl::Fixed),
+      autoplay: self.device.autoplay,
     };
 
     let mixer = Box::new(SoftMixer::open(MixerConfig {
-      volume_ctrl: VolumeCtrl::Linear,
+      volume_ctrl: self.device.volume_ctrl,
       ..MixerConfig::default()
     }));
 
@@ -260,22 +606,150 @@ impl SpotifyPlayer {
 
     let (spirc, task) = Spirc::new(config, cloned_session, player, mixer);
 
-    let handle = tokio::runtime::Handle::current();
-    handle.spawn(async {
+    let done = Arc::new(AtomicBool::new(false));
+    let cloned_done = done.clone();
+
+    self.spirc_task = Some(tokio::spawn(async move {
       task.await;
-    });
+      cloned_done.store(true, Ordering::Release);
+    }));
+    self.spirc_done = done;
 
     self.spirc = Some(Box::new(spirc));
-
-    let mut channel_lock = self.event_channel.as_ref().unwrap().lock().await;
-    *channel_lock = player_events;
+    self.connect_state = self.connect_state.on(ConnectEvent::Advertised);
+    self.events.forward(Source::Connect, player_events);
   }
 
+  /// Hides the device from the Spotify device picker. Does nothing if it already is.
   pub async fn disable_connect(&mut self) {
-    if let Some(spirc) = self.spirc.as_ref() {
-      spirc.shutdown();
+    self.connect_name = None;
+
+    let spirc = match self.spirc.take() {
+      Some(spirc) => spirc,
+      None => return,
+    };
+
+    // Stop forwarding first, so the player stopping is not mistaken for the user ending the cast
+    self.events.stop(Source::Connect);
 
-      self.event_channel.as_ref().unwrap().lock().await.close();
+    spirc.shutdown();
+    self.connect_state = self.connect_state.on(ConnectEvent::ShutdownStarted);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{wait_for_shutdown, ConnectEvent, ConnectState, Enable};
+
+  use tokio::time::Duration;
+
+  #[test]
+  fn casting_lifecycle() {
+    let advertised = ConnectState::Disconnected.on(ConnectEvent::Advertised);
+    assert_eq!(advertised, ConnectState::ConnectAdvertised);
+
+    let active = advertised.on(ConnectEvent::Activated);
+    assert_eq!(active, ConnectState::Active);
+    assert_eq!(
+      active.on(ConnectEvent::Deactivated),
+      ConnectState::ConnectAdvertised
+    );
+
+    let shutting_down = active.on(ConnectEvent::ShutdownStarted);
+    assert_eq!(shutting_down, ConnectState::ShuttingDown);
+    assert_eq!(
+      shutting_down.on(ConnectEvent::ShutdownFinished),
+      ConnectState::Disconnected
+    );
+  }
+
+  #[test]
+  fn rejoin_while_shutting_down() {
+    let shutting_down = ConnectState::Active.on(ConnectEvent::ShutdownStarted);
+
+    // The previous Spirc going inactive as it shuts down does not bring it back
+    assert_eq!(
+      shutting_down.on(ConnectEvent::Deactivated),
+      ConnectState::ShuttingDown
+    );
+    assert_eq!(
+      shutting_down.on(ConnectEvent::Activated),
+      ConnectState::ShuttingDown
+    );
+
+    let advertised = shutting_down.on(ConnectEvent::Advertised);
+    assert_eq!(advertised, ConnectState::ConnectAdvertised);
+    // The previous Spirc finishing afterwards does not take the new one down
+    assert_eq!(
+      advertised.on(ConnectEvent::ShutdownFinished),
+      ConnectState::ConnectAdvertised
+    );
+  }
+
+  #[test]
+  fn ignores_events_that_do_not_apply() {
+    assert_eq!(
+      ConnectState::Disconnected.on(ConnectEvent::Activated),
+      ConnectState::Disconnected
+    );
+    assert_eq!(
+      ConnectState::Disconnected.on(ConnectEvent::ShutdownStarted),
+      ConnectState::Disconnected
+    );
+    assert_eq!(
+      ConnectState::ConnectAdvertised.on(ConnectEvent::Deactivated),
+      ConnectState::ConnectAdvertised
+    );
+  }
+
+  #[test]
+  fn task_exiting_on_its_own() {
+    assert_eq!(
+      ConnectState::Active.on(ConnectEvent::TaskExited),
+      ConnectState::Disconnected
+    );
+    assert_eq!(
+      ConnectState::ConnectAdvertised.on(ConnectEvent::TaskExited),
+      ConnectState::Disconnected
+    );
+
+    // Enabling casting again starts a new Spirc under the same name
+    let exited = ConnectState::Active.on(ConnectEvent::TaskExited);
+    assert_eq!(
+      Enable::plan(exited, Some("Discord"), "Discord"),
+      Enable::Start
+    );
+  }
+
+  #[test]
+  fn enabling_twice() {
+    for state in [ConnectState::ConnectAdvertised, ConnectState::Active] {
+      assert_eq!(
+        Enable::plan(state, Some("Discord"), "Discord"),
+        Enable::Keep
+      );
+      assert_eq!(
+        Enable::plan(state, Some("Discord"), "Lounge"),
+        Enable::Restart
+      );
     }
+
+    assert_eq!(
+      Enable::plan(ConnectState::Disconnected, None, "Discord"),
+      Enable::Start
+    );
+    assert_eq!(
+      Enable::plan(ConnectState::ShuttingDown, None, "Discord"),
+      Enable::Start
+    );
+  }
+
+  #[tokio::test]
+  async fn waits_for_shutdown_up_to_the_limit() {
+    let finished = tokio::spawn(async {});
+    assert!(wait_for_shutdown(finished, Duration::from_secs(5)).await);
+
+    let stuck = tokio::spawn(std::future::pending());
+    assert!(!wait_for_shutdown(stuck, Duration::from_millis(50)).await);
   }
 }
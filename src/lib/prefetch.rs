@@ -0,0 +1,175 @@
+use crate::lib::now_playing;
+
+use librespot::audio::AudioFile;
+use librespot::core::{
+  session::Session,
+  spotify_id::{FileId, SpotifyId},
+};
+use librespot::metadata::{Album, FileFormat, Metadata, Playlist, Track};
+use librespot::playback::config::Bitrate;
+use log::*;
+use serenity::builder::CreateEmbed;
+
+use std::io;
+
+/// Roughly 320 kbps, only used to size librespot's read-ahead.
+const BYTES_PER_SECOND: usize = 40 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Collection {
+  Playlist(SpotifyId),
+  Album(SpotifyId),
+}
+
+/// Splits `spotify:playlist:<id>` style URIs and open.spotify.com links into their kind and id.
+pub fn split_uri(uri: &str) -> Option<(&str, SpotifyId)> {
+  let uri = uri.trim_start_matches("https://open.spotify.com/");
+  let uri = uri.split('?').next()?;
+
+  let mut parts = uri
+    .trim_start_matches("spotify:")
+    .split(|c| c == ':' || c == '/')
+    .filter(|part| !part.is_empty());
+
+  let kind = parts.next()?;
+  let id = SpotifyId::from_base62(parts.next()?).ok()?;
+
+  Some((kind, id))
+}
+
+impl Collection {
+  /// Accepts `spotify:playlist:<id>` style URIs and open.spotify.com links.
+  pub fn parse(uri: &str) -> Option<Collection> {
+    let (kind, id) = split_uri(uri)?;
+
+    match kind {
+      "playlist" => Some(Collection::Playlist(id)),
+      "album" => Some(Collection::Album(id)),
+      _ => None,
+    }
+  }
+
+  /// Name and tracks of the playlist or album.
+  pub async fn tracks(self, session: &Session) -> Option<(String, Vec<SpotifyId>)> {
+    match self {
+      Collection::Playlist(id) => {
+        let playlist = Playlist::get(session, id).await.ok()?;
+        Some((playlist.name, playlist.tracks))
+      }
+      Collection::Album(id) => {
+        let album = Album::get(session, id).await.ok()?;
+        Some((album.name, album.tracks))
+      }
+    }
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Progress {
+  pub total: usize,
+  pub downloaded: usize,
+  pub cached: usize,
+  pub failed: usize,
+}
+
+impl Progress {
+  pub fn done(&self) -> usize {
+    self.downloaded + self.cached + self.failed
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.done() >= self.total
+  }
+}
+
+/// Formats the player would pick for the bitrate, best match first.
+fn formats(bitrate: Bitrate) -> [FileFormat; 3] {
+  match bitrate {
+    Bitrate::Bitrate96 => [
+      FileFormat::OGG_VORBIS_96,
+      FileFormat::OGG_VORBIS_160,
+      FileFormat::OGG_VORBIS_320,
+    ],
+    Bitrate::Bitrate160 => [
+      FileFormat::OGG_VORBIS_160,
+      FileFormat::OGG_VORBIS_96,
+      FileFormat::OGG_VORBIS_320,
+    ],
+    Bitrate::Bitrate320 => [
+      FileFormat::OGG_VORBIS_320,
+      FileFormat::OGG_VORBIS_160,
+      FileFormat::OGG_VORBIS_96,
+    ],
+  }
+}
+
+/// The audio file the player would pick for the track at this bitrate.
+pub fn pick_file(track: &Track, bitrate: Bitrate) -> Option<FileId> {
+  formats(bitrate)
+    .iter()
+    .find_map(|format| track.files.get(format))
+    .copied()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fetched {
+  Downloaded,
+  AlreadyCached,
+}
+
+/// Downloads the track's audio file into the session's cache, unless it is there already.
+/// Playback then reads the audio from disk, but the key to decrypt it is still fetched when the
+/// track starts, so it won't play while Spotify is unreachable.
+pub async fn fetch_track(
+  session: &Session,
+  track_id: SpotifyId,
+  bitrate: Bitrate,
+) -> Result<Fetched, String> {
+  let cache = session.cache().ok_or("no cache configured")?.clone();
+
+  let track = Track::get(session, track_id)
+    .await
+    .map_err(|_| "could not fetch metadata")?;
+
+  let file_id = pick_file(&track, bitrate).ok_or("no audio file at this bitrate")?;
+
+  if cache.file(file_id).is_some() {
+    return Ok(Fetched::AlreadyCached);
+  }
+
+  let mut file = AudioFile::open(session, file_id, BYTES_PER_SECOND, true)
+    .await
+    .map_err(|why| format!("could not open audio file: {:?}", why))?;
+  file.get_stream_loader_controller().set_stream_mode();
+
+  // Reading to the end completes the download, librespot then writes it to the cache
+  tokio::task::spawn_blocking(move || io::copy(&mut file, &mut io::sink()))
+    .await
+    .map_err(|why| why.to_string())?
+    .map_err(|why| why.to_string())?;
+
+  debug!("Prefetched {} ({})", track.name, track_id.to_base62());
+
+  Ok(Fetched::Downloaded)
+}
+
+pub fn embed<'a>(e: &'a mut CreateEmbed, name: &str, progress: &Progress) -> &'a mut CreateEmbed {
+  let state = if progress.is_finished() {
+    "prefetched"
+  } else {
+    "prefetching"
+  };
+
+  e.title(format!("{} {}", state, name));
+  e.description(format!(
+    "{} `{} / {}`",
+    now_playing::progress_bar(progress.done() as u32, progress.total as u32, 16),
+    progress.done(),
+    progress.total
+  ));
+  e.field("downloaded", progress.downloaded, true);
+  e.field("already cached", progress.cached, true);
+  e.field("failed", progress.failed, true);
+
+  e
+}
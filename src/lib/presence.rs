@@ -0,0 +1,209 @@
+use crate::lib::events::{self as events, Event, GuildEvent, Source};
+use crate::lib::local::{self as local, LocalLibrary, LocalTrack};
+use crate::lib::users::LinkedUsers;
+
+use librespot::core::{
+  session::Session,
+  spotify_id::{SpotifyAudioType, SpotifyId},
+};
+use librespot::metadata::{Album, Artist, Episode, Metadata, Show, Track};
+use log::*;
+use serenity::client::Context;
+use serenity::model::{gateway::Activity, user::OnlineStatus};
+use serenity::prelude::TypeMapKey;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{sleep, Duration, Instant};
+
+use std::sync::Arc;
+
+/// Discord drops presence updates sent more often than this, so they are coalesced.
+const MIN_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Discord cuts activity names off beyond this.
+const MAX_LENGTH: usize = 128;
+
+/// Shown when nothing is known about what is playing.
+const FALLBACK: &str = "Spotify";
+
+/// What is playing, as far as its metadata could be fetched. Episodes show their publisher as
+/// the artist and their show as the album.
+#[derive(Debug, Default)]
+pub struct Playing {
+  pub name: Option<String>,
+  pub artists: Vec<String>,
+  pub album: Option<String>,
+}
+
+impl Playing {
+  pub async fn fetch(session: &Session, library: &LocalLibrary, id: SpotifyId) -> Playing {
+    // Spotify knows nothing about local tracks
+    if local::is_local(id) {
+      return library.get(id).map(Playing::local).unwrap_or_default();
+    }
+
+    match id.audio_type {
+      SpotifyAudioType::Podcast => Playing::fetch_episode(session, id).await,
+      _ => Playing::fetch_track(session, id).await,
+    }
+  }
+
+  fn local(track: &LocalTrack) -> Playing {
+    Playing {
+      name: Some(track.title.clone()),
+      artists: track.artists.clone(),
+      album: track.album.clone(),
+    }
+  }
+
+  async fn fetch_track(session: &Session, id: SpotifyId) -> Playing {
+    let track = match Track::get(session, id).await {
+      Ok(track) => track,
+      Err(why) => {
+        debug!("Could not fetch track {}: {:?}", id.to_base62(), why);
+        return Playing::default();
+      }
+    };
+
+    let mut artists = Vec::with_capacity(track.artists.len());
+    for artist_id in track.artists {
+      if let Ok(artist) = Artist::get(session, artist_id).await {
+        artists.push(artist.name);
+      }
+    }
+
+    Playing {
+      name: Some(track.name),
+      artists,
+      album: Album::get(session, track.album)
+        .await
+        .ok()
+        .map(|album| album.name),
+    }
+  }
+
+  async fn fetch_episode(session: &Session, id: SpotifyId) -> Playing {
+    let episode = match Episode::get(session, id).await {
+      Ok(episode) => episode,
+      Err(why) => {
+        debug!("Could not fetch episode {}: {:?}", id.to_base62(), why);
+        return Playing::default();
+      }
+    };
+
+    let show = Show::get(session, episode.show).await.ok();
+
+    Playing {
+      name: Some(episode.name),
+      artists: show
+        .as_ref()
+        .map(|show| vec![show.publisher.clone()])
+        .unwrap_or_default(),
+      album: show.map(|show| show.name),
+    }
+  }
+}
+
+/// Fills the `PRESENCE_FORMAT` template. Supports `{track}`, `{artist}` for the first artist,
+/// `{artists}` for all of them, and `{album}`.
+pub struct Presence {
+  template: String,
+}
+
+impl Presence {
+  pub fn new(template: String) -> Presence {
+    Presence { template }
+  }
+
+  /// Falls back to just the name when the template needs metadata that could not be fetched.
+  pub fn render(&self, playing: &Playing) -> String {
+    let name = match &playing.name {
+      Some(name) => name,
+      None => return FALLBACK.to_string(),
+    };
+
+    let needs_artists = self.template.contains("{artist}") || self.template.contains("{artists}");
+    let needs_album = self.template.contains("{album}");
+
+    if (needs_artists && playing.artists.is_empty()) || (needs_album && playing.album.is_none()) {
+      return truncate(name);
+    }
+
+    let rendered = self
+      .template
+      .replace("{track}", name)
+      .replace("{artists}", &playing.artists.join(", "))
+      .replace(
+        "{artist}",
+        playing.artists.first().map(String::as_str).unwrap_or(""),
+      )
+      .replace("{album}", playing.album.as_deref().unwrap_or(""));
+
+    truncate(&rendered)
+  }
+}
+
+fn truncate(activity: &str) -> String {
+  activity.chars().take(MAX_LENGTH).collect()
+}
+
+/// Shows what is being cast or played with `!play` as the bot's activity.
+/// Updates arriving faster than Discord allows replace each other, only the latest is sent.
+pub async fn update_loop(
+  ctx: Context,
+  presence: Arc<Presence>,
+  linked: Arc<Mutex<LinkedUsers>>,
+  library: Arc<LocalLibrary>,
+  mut receiver: broadcast::Receiver<GuildEvent>,
+) {
+  // `Some(None)` clears the activity
+  let mut pending: Option<Option<String>> = None;
+  let mut last_update: Option<Instant> = None;
+
+  loop {
+    let wait = last_update
+      .map(|at| MIN_INTERVAL.saturating_sub(at.elapsed()))
+      .unwrap_or_default();
+
+    tokio::select! {
+      e = events::next(&mut receiver, "presence") => {
+        let e = match e {
+          Some(e) => e,
+          None => break,
+        };
+
+        match e.event {
+          Event::Playing { track_id, .. } => {
+            let player = match linked.lock().await.get(e.user_id) {
+              Some(player) => player,
+              None => continue,
+            };
+            let session = player.lock().await.session.clone();
+
+            let playing = Playing::fetch(&session, &library, track_id).await;
+            pending = Some(Some(presence.render(&playing)));
+          }
+
+          Event::Paused { .. } | Event::Stopped => pending = Some(None),
+
+          // Casting moves on to the next track by itself, the `!play` queue may have run out
+          Event::EndOfTrack if e.source == Source::Direct => pending = Some(None),
+
+          _ => {}
+        }
+      }
+
+      _ = sleep(wait), if pending.is_some() => {
+        let activity = pending.take().unwrap().map(Activity::listening);
+
+        ctx.set_presence(activity, OnlineStatus::Online).await;
+        last_update = Some(Instant::now());
+      }
+    }
+  }
+}
+
+pub struct PresenceKey;
+
+impl TypeMapKey for PresenceKey {
+  type Value = Arc<Presence>;
+}
@@ -0,0 +1,46 @@
+use crate::lib::prefetch;
+
+use librespot::core::{
+  session::Session,
+  spotify_id::{SpotifyAudioType, SpotifyId},
+};
+use librespot::metadata::{Metadata, Show};
+
+/// Something `!play` accepts.
+#[derive(Debug, Clone, Copy)]
+pub enum Playable {
+  Track(SpotifyId),
+  Episode(SpotifyId),
+  Show(SpotifyId),
+}
+
+impl Playable {
+  /// Accepts `spotify:episode:<id>` style URIs and open.spotify.com links.
+  pub fn parse(uri: &str) -> Option<Playable> {
+    let (kind, id) = prefetch::split_uri(uri)?;
+
+    match kind {
+      "track" => Some(Playable::Track(id)),
+      "episode" => Some(Playable::Episode(episode(id))),
+      "show" => Some(Playable::Show(id)),
+      _ => None,
+    }
+  }
+
+  /// Ids to queue, a show's episodes in the order the show lists them.
+  pub async fn ids(self, session: &Session) -> Option<Vec<SpotifyId>> {
+    match self {
+      Playable::Track(id) | Playable::Episode(id) => Some(vec![id]),
+      Playable::Show(id) => {
+        let show = Show::get(session, id).await.ok()?;
+        Some(show.episodes.into_iter().map(episode).collect())
+      }
+    }
+  }
+}
+
+/// Parsed ids default to tracks, the player needs to know to fetch episode metadata instead.
+fn episode(mut id: SpotifyId) -> SpotifyId {
+  id.audio_type = SpotifyAudioType::Podcast;
+  id
+}
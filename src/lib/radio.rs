@@ -0,0 +1,112 @@
+use crate::lib::history::History;
+
+use chrono::Utc;
+use librespot::core::{mercury::MercuryError, session::Session, spotify_id::SpotifyId};
+use log::*;
+use serenity::{model::id, prelude::TypeMapKey};
+use tokio::sync::Mutex;
+
+use std::sync::Arc;
+
+/// Most tracks queued from a station at once.
+const BATCH: usize = 20;
+
+/// Keeps `!play` going with Spotify's autoplay station once the queue runs out, the same one the
+/// Spotify app continues with.
+pub struct Radio {
+  /// Tracks played in the guild this recently are left out.
+  repeat_hours: u64,
+}
+
+impl Radio {
+  pub fn new(repeat_hours: u64) -> Radio {
+    Radio { repeat_hours }
+  }
+
+  /// Tracks following the guild's most recently played one.
+  pub async fn next_tracks(
+    &self,
+    session: &Session,
+    history: &Mutex<History>,
+    guild_id: id::GuildId,
+  ) -> Vec<SpotifyId> {
+    let window =
+      i64::try_from(self.repeat_hours).map_or(i64::MAX, |hours| hours.saturating_mul(3600));
+    let since = Utc::now().timestamp().saturating_sub(window);
+
+    let (seed, recent) = {
+      let history = history.lock().await;
+      (
+        history.last_track(guild_id),
+        history.played_since(guild_id, since),
+      )
+    };
+
+    let (seed, recent) = match (seed, recent) {
+      (Ok(Some(seed)), Ok(recent)) => (seed, recent),
+      (Ok(None), _) => return Vec::new(),
+      (Err(why), _) | (_, Err(why)) => {
+        warn!("Could not read history for autoplay: {:?}", why);
+        return Vec::new();
+      }
+    };
+
+    let tracks = match station(session, seed).await {
+      Ok(tracks) => tracks,
+      Err(why) => {
+        debug!("No autoplay station for {}: {:?}", seed.to_base62(), why);
+        return Vec::new();
+      }
+    };
+
+    tracks
+      .into_iter()
+      .filter(|track_id| !recent.contains(track_id))
+      .take(BATCH)
+      .collect()
+  }
+}
+
+/// Tracks of the autoplay station for `seed`, resolved the way librespot's Spirc does.
+async fn station(session: &Session, seed: SpotifyId) -> Result<Vec<SpotifyId>, MercuryError> {
+  let response = session
+    .mercury()
+    .get(format!("hm://autoplay-enabled/query?uri={}", seed.to_uri()))
+    .await?;
+  let station_uri = response
+    .payload
+    .first()
+    .map(|payload| String::from_utf8_lossy(payload).into_owned())
+    .ok_or(MercuryError)?;
+
+  let response = session
+    .mercury()
+    .get(format!(
+      "hm://radio-apollo/v3/stations/{}?autoplay=true",
+      station_uri
+    ))
+    .await?;
+  let context: serde_json::Value = response
+    .payload
+    .first()
+    .and_then(|payload| serde_json::from_slice(payload).ok())
+    .ok_or(MercuryError)?;
+
+  let tracks = context["tracks"]
+    .as_array()
+    .map(|tracks| {
+      tracks
+        .iter()
+        .filter_map(|track| SpotifyId::from_uri(track["uri"].as_str()?).ok())
+        .collect()
+    })
+    .unwrap_or_default();
+
+  Ok(tracks)
+}
+
+pub struct RadioKey;
+
+impl TypeMapKey for RadioKey {
+  type Value = Arc<Radio>;
+}
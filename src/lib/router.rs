@@ -0,0 +1,207 @@
+use crate::lib::events::{self as events, Event, GuildEvent, Source};
+use crate::lib::guilds::Guilds;
+use crate::lib::history::History;
+use crate::lib::local::{self as local, LocalLibrary};
+use crate::lib::now_playing::NowPlaying;
+use crate::lib::source::AudioSource;
+
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use log::*;
+use serenity::async_trait;
+use serenity::model::id;
+use tokio::sync::{broadcast, Mutex};
+
+use std::sync::Arc;
+
+/// Whatever routing needs beyond guild state and the player, i.e. anything that reaches into
+/// Discord or Spotify.
+#[async_trait]
+pub trait Hooks: Send + Sync {
+  /// Casting started or stopped on the user's device, before the event is routed. Casting picks
+  /// its guild here.
+  async fn casting(&self, active: bool);
+
+  /// Whether the track is kept from playing in the guild. Skipped tracks are not routed.
+  async fn skip_filtered(&self, guild_id: id::GuildId, source: Source, track_id: SpotifyId)
+    -> bool;
+
+  /// The queue ran out after a track we loaded ourselves ended.
+  async fn queue_ended(&self, guild_id: id::GuildId);
+
+  /// A casting event was routed to the guild.
+  async fn cast_event(&self, guild_id: id::GuildId, event: Event);
+}
+
+/// Routes a user's player events to the guild they are casting in. Casting state is kept up to
+/// date here, before anything subscribed to guild events sees them.
+pub struct Router<P, H> {
+  pub user_id: id::UserId,
+  pub player: Arc<Mutex<P>>,
+  pub guilds: Arc<Mutex<Guilds>>,
+  pub history: Arc<Mutex<History>>,
+  pub library: Arc<LocalLibrary>,
+  pub guild_events: broadcast::Sender<GuildEvent>,
+  pub hooks: H,
+}
+
+impl<P: AudioSource, H: Hooks> Router<P, H> {
+  /// Routes events until the player's event bus closes.
+  pub async fn run(self, mut receiver: broadcast::Receiver<(Source, Event)>) {
+    while let Some((source, event)) = events::next(&mut receiver, "router").await {
+      self.route(source, event).await;
+    }
+  }
+
+  async fn route(&self, source: Source, event: Event) {
+    if source == Source::Connect {
+      match event {
+        Event::Started => self.hooks.casting(true).await,
+        Event::Stopped => self.hooks.casting(false).await,
+        _ => {}
+      }
+    }
+
+    // Filtered tracks are skipped before anything else sees them
+    if let Event::Playing { track_id, .. } = event {
+      let guild_id = self.guilds.lock().await.cast_by(self.user_id);
+
+      if let Some(guild_id) = guild_id {
+        if self.hooks.skip_filtered(guild_id, source, track_id).await {
+          return;
+        }
+      }
+    }
+
+    // Only whoever is casting in a guild drives its audio
+    let mut guilds = self.guilds.lock().await;
+    let guild_id = match guilds.cast_by(self.user_id) {
+      Some(guild_id) => guild_id,
+      None => return,
+    };
+    let previous = guilds.now_playing(guild_id);
+
+    match event {
+      Event::Playing {
+        track_id,
+        position_ms,
+        duration_ms,
+      } => {
+        guilds.get_mut(guild_id).now_playing = Some(NowPlaying::new(
+          track_id,
+          guild_id,
+          position_ms,
+          duration_ms,
+          Some(self.user_id),
+        ));
+      }
+
+      Event::Paused { position_ms } => {
+        if let Some(now_playing) = guilds.get_mut(guild_id).now_playing.as_mut() {
+          now_playing.pause(position_ms);
+        }
+      }
+
+      Event::Stopped | Event::EndOfTrack | Event::Unavailable { .. } => {
+        guilds.get_mut(guild_id).now_playing = None;
+      }
+
+      _ => {}
+    }
+    drop(guilds);
+
+    // Nobody subscribed is fine
+    let _ = self.guild_events.send(GuildEvent {
+      guild_id,
+      user_id: self.user_id,
+      source,
+      event,
+    });
+
+    // Episodes are picked back up where they were left
+    if let Some(previous) =
+      previous.filter(|previous| previous.track_id.audio_type == SpotifyAudioType::Podcast)
+    {
+      let history = self.history.lock().await;
+      let result = match event {
+        Event::Paused { position_ms } => history.save_position(previous.track_id, position_ms),
+        Event::Stopped => history.save_position(previous.track_id, previous.position_ms()),
+        Event::EndOfTrack => history.clear_position(previous.track_id),
+        _ => Ok(()),
+      };
+
+      if let Err(why) = result {
+        warn!("Could not save episode position: {:?}", why);
+      }
+    }
+
+    if source == Source::Connect {
+      self.hooks.cast_event(guild_id, event).await;
+      return;
+    }
+
+    // Tracks we load ourselves only need to keep `!np`, snapshots and the queue up to date
+    match event {
+      Event::EndOfTrack => {
+        if self.play_next(guild_id).await.is_none() {
+          self.hooks.queue_ended(guild_id).await;
+        }
+      }
+      // Not continuing with the radio here, it could keep suggesting the same unavailable tracks
+      Event::Unavailable { .. } => {
+        self.play_next(guild_id).await;
+      }
+      _ => {}
+    }
+  }
+
+  async fn play_next(&self, guild_id: id::GuildId) -> Option<SpotifyId> {
+    play_next(
+      &self.player,
+      &self.guilds,
+      &self.history,
+      &self.library,
+      guild_id,
+    )
+    .await
+  }
+}
+
+/// Plays the next item queued with `!play` in the guild, picking episodes up where they were left.
+pub async fn play_next<P: AudioSource>(
+  player: &Arc<Mutex<P>>,
+  guilds: &Arc<Mutex<Guilds>>,
+  history: &Arc<Mutex<History>>,
+  library: &LocalLibrary,
+  guild_id: id::GuildId,
+) -> Option<SpotifyId> {
+  let next = loop {
+    let next = guilds.lock().await.get_mut(guild_id).queue.pop_front()?;
+
+    if !local::is_local(next) {
+      break next;
+    }
+
+    match library.get(next) {
+      Some(track) => {
+        player.lock().await.load_local(next, track.clone());
+        return Some(next);
+      }
+      None => warn!("{} is no longer in the local library", next.to_base62()),
+    }
+  };
+
+  let position_ms = match next.audio_type {
+    SpotifyAudioType::Podcast => history.lock().await.position(next).unwrap_or_else(|why| {
+      warn!("Could not look up episode position: {:?}", why);
+      None
+    }),
+    _ => None,
+  };
+
+  player
+    .lock()
+    .await
+    .load(next, position_ms.unwrap_or(0), true);
+
+  Some(next)
+}
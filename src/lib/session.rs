@@ -0,0 +1,131 @@
+use crate::lib::player::SpotifyPlayer;
+
+use chrono::{DateTime, Utc};
+use librespot::core::session::Session;
+use log::*;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+
+const STATUS_LOG_SIZE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionState {
+  Connected,
+  Reconnecting { attempt: u32 },
+}
+
+impl fmt::Display for SessionState {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SessionState::Connected => write!(f, "connected"),
+      SessionState::Reconnecting { attempt } => write!(f, "reconnecting (attempt {})", attempt),
+    }
+  }
+}
+
+/// Session health, surfaced through `!status`.
+pub struct SessionStatus {
+  pub state: SessionState,
+  pub since: DateTime<Utc>,
+  pub reconnects: u32,
+  pub log: VecDeque<(DateTime<Utc>, String)>,
+}
+
+impl Default for SessionStatus {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SessionStatus {
+  pub fn new() -> SessionStatus {
+    let mut status = SessionStatus {
+      state: SessionState::Connected,
+      since: Utc::now(),
+      reconnects: 0,
+      log: VecDeque::with_capacity(STATUS_LOG_SIZE),
+    };
+    status.record("session connected");
+
+    status
+  }
+
+  pub fn set_state(&mut self, state: SessionState) {
+    if state == SessionState::Connected && self.state != SessionState::Connected {
+      self.reconnects += 1;
+    }
+
+    self.state = state;
+    self.since = Utc::now();
+  }
+
+  pub fn record(&mut self, step: impl Into<String>) {
+    if self.log.len() == STATUS_LOG_SIZE {
+      self.log.pop_front();
+    }
+
+    self.log.push_back((Utc::now(), step.into()));
+  }
+}
+
+/// Watches the session and, once Spotify drops it, reconnects with exponential backoff and
+/// rebuilds the players on the new session.
+pub async fn watch(player: Arc<Mutex<SpotifyPlayer>>) {
+  loop {
+    sleep(Duration::from_secs(5)).await;
+
+    if !player.lock().await.session.is_invalid() {
+      continue;
+    }
+
+    warn!("Spotify session dropped, reconnecting");
+    player.lock().await.status.record("session dropped");
+
+    let mut backoff = Duration::from_secs(1);
+    let mut attempt = 1;
+
+    let session = loop {
+      let (session_config, credentials, cache) = {
+        let mut player = player.lock().await;
+        player
+          .status
+          .set_state(SessionState::Reconnecting { attempt });
+
+        player.session_params()
+      };
+
+      match Session::connect(session_config, credentials, cache).await {
+        Ok(session) => break session,
+        Err(why) => {
+          warn!(
+            "Reconnect attempt {} failed: {:?}, retrying in {}s",
+            attempt,
+            why,
+            backoff.as_secs()
+          );
+          player
+            .lock()
+            .await
+            .status
+            .record(format!("reconnect attempt {} failed", attempt));
+
+          sleep(backoff).await;
+          backoff = (backoff * 2).min(Duration::from_secs(5 * 60));
+          attempt += 1;
+        }
+      }
+    };
+
+    info!("Spotify session reconnected, rebuilding players");
+
+    let mut player = player.lock().await;
+    player.status.record("session reconnected");
+    player.replace_session(session).await;
+    player.status.set_state(SessionState::Connected);
+    player.status.record("players rebuilt");
+  }
+}
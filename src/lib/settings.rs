@@ -0,0 +1,85 @@
+use crate::lib::player::Quality;
+
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use serenity::{model::id, prelude::TypeMapKey};
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{fs, io};
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GuildSettings {
+  pub announce_channel: Option<u64>,
+  pub recap_channel: Option<u64>,
+  pub last_recap: Option<i64>,
+  /// Shown in the Spotify device picker instead of the configured name.
+  pub device_name: Option<String>,
+  /// Overrides the configured quality.
+  pub quality: Option<Quality>,
+  /// Last volume set from the Spotify app, 0 to 65535.
+  pub volume: Option<u16>,
+  /// Continue with the radio once the `!play` queue runs out, overrides the configured autoplay.
+  pub autoplay: Option<bool>,
+  /// Track, artist and album URIs skipped whenever they come up.
+  pub blocked: BTreeSet<String>,
+  /// Skip tracks and episodes Spotify flags as explicit.
+  pub skip_explicit: bool,
+}
+
+/// Per-guild settings, persisted as JSON so they survive restarts.
+pub struct Settings {
+  path: PathBuf,
+  guilds: HashMap<u64, GuildSettings>,
+}
+
+impl Settings {
+  pub fn load(path: impl Into<PathBuf>) -> Settings {
+    let path = path.into();
+
+    let guilds = match fs::read(&path) {
+      Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|why| {
+        warn!("Could not parse {}: {:?}", path.display(), why);
+        HashMap::new()
+      }),
+      Err(_) => HashMap::new(),
+    };
+
+    Settings { path, guilds }
+  }
+
+  pub fn guild(&self, guild_id: id::GuildId) -> GuildSettings {
+    self.guilds.get(&guild_id.0).cloned().unwrap_or_default()
+  }
+
+  pub fn guilds(&self) -> Vec<(id::GuildId, GuildSettings)> {
+    self
+      .guilds
+      .iter()
+      .map(|(guild_id, settings)| (id::GuildId(*guild_id), settings.clone()))
+      .collect()
+  }
+
+  pub fn update<F>(&mut self, guild_id: id::GuildId, f: F) -> io::Result<()>
+  where
+    F: FnOnce(&mut GuildSettings),
+  {
+    f(self.guilds.entry(guild_id.0).or_default());
+
+    self.save()
+  }
+
+  fn save(&self) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(&self.guilds)?;
+
+    fs::write(&self.path, json)
+  }
+}
+
+pub struct SettingsKey;
+
+impl TypeMapKey for SettingsKey {
+  type Value = Arc<tokio::sync::Mutex<Settings>>;
+}
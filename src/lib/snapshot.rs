@@ -0,0 +1,263 @@
+use crate::lib::guilds::Guilds;
+use crate::lib::now_playing::{self, NowPlaying};
+
+use chrono::Utc;
+use librespot::core::spotify_id::SpotifyId;
+use log::*;
+use serde_derive::{Deserialize, Serialize};
+use serenity::prelude::TypeMapKey;
+use songbird::Songbird;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::{fs, io};
+
+#[derive(Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResumeMode {
+  Auto,
+  Ask,
+  Off,
+}
+impl Default for ResumeMode {
+  fn default() -> Self {
+    Self::Ask
+  }
+}
+
+/// Playback state at the time it was saved, enough to pick up where we left off.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub guild_id: u64,
+  pub voice_channel_id: u64,
+  #[serde(default)]
+  pub user_id: Option<u64>,
+  /// A URI, or a bare track id in snapshots from before episodes were supported.
+  pub track_id: String,
+  pub position_ms: u32,
+  pub paused: bool,
+  pub saved_at: i64,
+  /// URIs still queued with `!play`.
+  #[serde(default)]
+  pub queue: Vec<String>,
+}
+
+impl Snapshot {
+  /// Captures what is playing and where, if we are still in voice.
+  pub async fn take(
+    now_playing: &NowPlaying,
+    queue: &[SpotifyId],
+    manager: &Songbird,
+  ) -> Option<Snapshot> {
+    let voice_channel_id = manager
+      .get(now_playing.guild_id)?
+      .lock()
+      .await
+      .current_channel()?;
+
+    Some(Snapshot {
+      guild_id: now_playing.guild_id.0,
+      voice_channel_id: voice_channel_id.0,
+      user_id: now_playing.requested_by.map(|user_id| user_id.0),
+      track_id: now_playing::format_id(now_playing.track_id),
+      position_ms: now_playing.position_ms(),
+      paused: now_playing.paused,
+      saved_at: Utc::now().timestamp(),
+      queue: queue.iter().copied().map(now_playing::format_id).collect(),
+    })
+  }
+
+  /// One snapshot for every guild something is playing in.
+  pub async fn take_all(guilds: &Mutex<Guilds>, manager: &Songbird) -> Vec<Snapshot> {
+    let playing = {
+      let guilds = guilds.lock().await;
+
+      guilds
+        .all_now_playing()
+        .into_iter()
+        .map(|now_playing| {
+          let queue = guilds.queue(now_playing.guild_id);
+          (now_playing, queue)
+        })
+        .collect::<Vec<_>>()
+    };
+
+    let mut snapshots = Vec::with_capacity(playing.len());
+    for (now_playing, queue) in &playing {
+      if let Some(snapshot) = Snapshot::take(now_playing, queue, manager).await {
+        snapshots.push(snapshot);
+      }
+    }
+
+    snapshots
+  }
+}
+
+pub struct Snapshots {
+  path: PathBuf,
+  pub resume: ResumeMode,
+  /// Snapshots saved longer ago than this are not resumed.
+  max_age_secs: i64,
+}
+
+impl Snapshots {
+  pub fn new(path: impl Into<PathBuf>, resume: ResumeMode, max_age_hours: u64) -> Snapshots {
+    Snapshots {
+      path: path.into(),
+      resume,
+      max_age_secs: max_age_hours.saturating_mul(3600).min(i64::MAX as u64) as i64,
+    }
+  }
+
+  /// Snapshots recent enough to resume.
+  pub fn load(&self) -> Vec<Snapshot> {
+    let oldest = Utc::now().timestamp().saturating_sub(self.max_age_secs);
+
+    self
+      .load_all()
+      .into_iter()
+      .filter(|snapshot| {
+        let recent = snapshot.saved_at >= oldest;
+        if !recent {
+          debug!(
+            "Discarding snapshot of guild {}, too old",
+            snapshot.guild_id
+          );
+        }
+        recent
+      })
+      .collect()
+  }
+
+  fn load_all(&self) -> Vec<Snapshot> {
+    let bytes = match fs::read(&self.path) {
+      Ok(bytes) => bytes,
+      Err(_) => return Vec::new(),
+    };
+
+    // Snapshots saved before multi-guild support hold a single guild
+    let snapshots = serde_json::from_slice::<Vec<Snapshot>>(&bytes)
+      .or_else(|_| serde_json::from_slice::<Snapshot>(&bytes).map(|snapshot| vec![snapshot]));
+
+    match snapshots {
+      Ok(snapshots) => snapshots,
+      Err(why) => {
+        warn!("Could not parse {}: {:?}", self.path.display(), why);
+        Vec::new()
+      }
+    }
+  }
+
+  pub fn load_guild(&self, guild_id: u64) -> Option<Snapshot> {
+    self
+      .load()
+      .into_iter()
+      .find(|snapshot| snapshot.guild_id == guild_id)
+  }
+
+  /// Drops the guild's snapshot once it was resumed or declined, along with any too old to resume.
+  pub fn remove(&self, guild_id: u64) -> io::Result<()> {
+    let remaining = self
+      .load()
+      .into_iter()
+      .filter(|snapshot| snapshot.guild_id != guild_id)
+      .collect::<Vec<_>>();
+
+    self.save(&remaining)
+  }
+
+  /// Saves the snapshots, or removes the previous ones when nothing is playing.
+  pub fn save(&self, snapshots: &[Snapshot]) -> io::Result<()> {
+    if !snapshots.is_empty() {
+      return fs::write(&self.path, serde_json::to_vec_pretty(snapshots)?);
+    }
+
+    match fs::remove_file(&self.path) {
+      Err(why) if why.kind() != io::ErrorKind::NotFound => Err(why),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Saves snapshots every half minute while something is playing. Once playback ends everywhere
+/// they are removed, but ones left over from before a restart are kept until resumed or replaced.
+pub async fn save_loop(
+  snapshots: Arc<Snapshots>,
+  guilds: Arc<Mutex<Guilds>>,
+  manager: Arc<Songbird>,
+) {
+  let mut saved = false;
+
+  loop {
+    sleep(Duration::from_secs(30)).await;
+
+    let current = Snapshot::take_all(&guilds, &manager).await;
+
+    if current.is_empty() && !saved {
+      continue;
+    }
+
+    if let Err(why) = snapshots.save(&current) {
+      warn!("Could not save snapshot: {:?}", why);
+    }
+
+    saved = !current.is_empty();
+  }
+}
+
+pub struct SnapshotsKey;
+
+impl TypeMapKey for SnapshotsKey {
+  type Value = Arc<Snapshots>;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn snapshot(guild_id: u64, saved_at: i64) -> Snapshot {
+    Snapshot {
+      guild_id,
+      voice_channel_id: 1,
+      user_id: None,
+      track_id: "spotify:track:4uLU6hMCjMI75M1A2tKUQC".to_string(),
+      position_ms: 0,
+      paused: false,
+      saved_at,
+      queue: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn skips_old_and_removes_resumed() {
+    let path = std::env::temp_dir().join(format!("snapshot-test-{}.json", std::process::id()));
+    let snapshots = Snapshots::new(&path, ResumeMode::Auto, 12);
+    let now = Utc::now().timestamp();
+
+    snapshots
+      .save(&[
+        snapshot(1, now),
+        snapshot(2, now),
+        snapshot(3, now - 13 * 3600),
+      ])
+      .unwrap();
+
+    let guilds = |snapshots: &Snapshots| {
+      snapshots
+        .load()
+        .iter()
+        .map(|snapshot| snapshot.guild_id)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(guilds(&snapshots), vec![1, 2]);
+
+    snapshots.remove(1).unwrap();
+    assert_eq!(guilds(&snapshots), vec![2]);
+
+    // The file goes away with the last one
+    snapshots.remove(2).unwrap();
+    assert!(!path.exists());
+  }
+}
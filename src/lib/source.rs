@@ -0,0 +1,246 @@
+use crate::lib::events::{Event, PlayerEvents, Source};
+use crate::lib::local::{LocalPlayback, LocalTrack};
+use crate::lib::player::{EmittedSink, SpotifyPlayer};
+
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use librespot::playback::{audio_backend::Sink, convert::Converter, decoder::AudioPacket};
+use log::*;
+use tokio::sync::broadcast;
+
+use std::f64::consts::PI;
+use std::sync::{
+  atomic::{AtomicBool, Ordering},
+  Arc,
+};
+use std::thread;
+use std::time::Duration;
+
+/// Frames written to the sink at a time.
+const CHUNK_FRAMES: usize = 1024;
+
+/// Loud enough to hear, quiet enough not to hurt.
+const AMPLITUDE: f64 = 0.2;
+
+/// What voice and the event loop need from a player: audio to stream, something to load into it,
+/// and the events that result.
+pub trait AudioSource: Send {
+  /// Stream this into voice, it carries whatever gets loaded.
+  fn sink(&self) -> EmittedSink;
+
+  /// `None` once the source is shut down.
+  fn subscribe(&self) -> Option<broadcast::Receiver<(Source, Event)>>;
+
+  fn load(&mut self, track_id: SpotifyId, position_ms: u32, start_playing: bool);
+
+  fn load_local(&mut self, track_id: SpotifyId, track: LocalTrack);
+
+  fn stop(&self);
+}
+
+impl AudioSource for SpotifyPlayer {
+  fn sink(&self) -> EmittedSink {
+    self.emitted_sink.clone()
+  }
+
+  fn subscribe(&self) -> Option<broadcast::Receiver<(Source, Event)>> {
+    SpotifyPlayer::subscribe(self)
+  }
+
+  fn load(&mut self, track_id: SpotifyId, position_ms: u32, start_playing: bool) {
+    SpotifyPlayer::load(self, track_id, position_ms, start_playing)
+  }
+
+  fn load_local(&mut self, track_id: SpotifyId, track: LocalTrack) {
+    SpotifyPlayer::load_local(self, track_id, track)
+  }
+
+  fn stop(&self) {
+    self.stop_direct()
+  }
+}
+
+/// A tone being written into the sink by its own thread.
+struct Tone {
+  stop: Arc<AtomicBool>,
+  thread: thread::JoinHandle<()>,
+}
+
+impl Tone {
+  /// Stops the tone and waits for its thread, so it is not writing alongside the next one.
+  fn join(self, sink: &EmittedSink) {
+    self.stop.store(true, Ordering::Release);
+
+    // The thread blocks while nobody reads the sink, so keep reading until it is done. Voice may
+    // still be reading too, in which case it holds the receiver.
+    while !self.thread.is_finished() {
+      match sink.receiver.try_lock() {
+        Ok(receiver) => {
+          let _ = receiver.recv_timeout(Duration::from_millis(10));
+        }
+        Err(_) => thread::sleep(Duration::from_millis(10)),
+      }
+    }
+
+    if self.thread.join().is_err() {
+      warn!("Tone thread panicked");
+    }
+  }
+}
+
+/// What a `SyntheticSource` is playing.
+enum Playback {
+  Tone(Tone),
+  File(LocalPlayback),
+}
+
+impl Playback {
+  /// Returns whether it was still playing.
+  fn stop(&self) -> bool {
+    match self {
+      Playback::Tone(tone) => !tone.stop.swap(true, Ordering::AcqRel),
+      Playback::File(local) => local.stop(),
+    }
+  }
+}
+
+/// Plays a sine tone, or a local file, without a Spotify session. Events are sent as the direct
+/// player would send them, so anything driven by a player's events can be driven by this instead.
+pub struct SyntheticSource {
+  sink: EmittedSink,
+  events: PlayerEvents,
+  /// Any id loaded plays a tone of this length.
+  tone_ms: u32,
+  playback: Option<Playback>,
+}
+
+impl SyntheticSource {
+  pub fn new(tone_ms: u32) -> SyntheticSource {
+    SyntheticSource {
+      sink: EmittedSink::new(),
+      events: PlayerEvents::new(),
+      tone_ms,
+      playback: None,
+    }
+  }
+
+  /// An id for loading a tone, nothing else uses it.
+  pub fn tone_id() -> SpotifyId {
+    SpotifyId {
+      id: 0,
+      audio_type: SpotifyAudioType::NonPlayable,
+    }
+  }
+
+  /// Stops whatever plays. A tone is waited for, it would interleave with what plays next.
+  fn end_previous(&mut self) {
+    match self.playback.take() {
+      Some(Playback::Tone(tone)) => tone.join(&self.sink),
+      Some(Playback::File(local)) => {
+        local.stop();
+      }
+      None => {}
+    }
+  }
+
+  /// Ends the event bus, subscribers see it close.
+  pub fn close(&mut self) {
+    AudioSource::stop(self);
+    self.events.close();
+  }
+}
+
+impl AudioSource for SyntheticSource {
+  fn sink(&self) -> EmittedSink {
+    self.sink.clone()
+  }
+
+  fn subscribe(&self) -> Option<broadcast::Receiver<(Source, Event)>> {
+    self.events.subscribe()
+  }
+
+  /// Plays a 440 Hz tone from `position_ms`, whatever the id.
+  fn load(&mut self, track_id: SpotifyId, position_ms: u32, _start_playing: bool) {
+    self.end_previous();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (sink, events, duration_ms) = (self.sink.clone(), self.events.sender(), self.tone_ms);
+    let cloned_stop = stop.clone();
+
+    let thread = thread::spawn(move || {
+      let send = |event| {
+        if let Some(events) = &events {
+          let _ = events.send((Source::Direct, event));
+        }
+      };
+
+      send(Event::Loading { track_id });
+      send(Event::Playing {
+        track_id,
+        position_ms,
+        duration_ms,
+      });
+
+      let ended = play_tone(sink, 440.0, position_ms, duration_ms, &cloned_stop);
+      if ended && !cloned_stop.swap(true, Ordering::AcqRel) {
+        send(Event::EndOfTrack);
+      }
+    });
+
+    self.playback = Some(Playback::Tone(Tone { stop, thread }));
+  }
+
+  fn load_local(&mut self, track_id: SpotifyId, track: LocalTrack) {
+    self.end_previous();
+
+    let local = LocalPlayback::start(track_id, track, self.sink.clone(), self.events.sender());
+    self.playback = Some(Playback::File(local));
+  }
+
+  fn stop(&self) {
+    let stopped = self
+      .playback
+      .as_ref()
+      .map_or(false, |playback| playback.stop());
+
+    if let (true, Some(events)) = (stopped, self.events.sender()) {
+      let _ = events.send((Source::Direct, Event::Stopped));
+    }
+  }
+}
+
+/// Writes the tone into the sink at librespot's sample rate, so it goes through the same
+/// resampling as Spotify's audio. Returns whether it played to its end, rather than being stopped.
+fn play_tone(
+  mut sink: EmittedSink,
+  frequency: f64,
+  position_ms: u32,
+  duration_ms: u32,
+  stop: &AtomicBool,
+) -> bool {
+  let rate = librespot::playback::SAMPLE_RATE as u64;
+  let mut frame = position_ms as u64 * rate / 1000;
+  let end = duration_ms as u64 * rate / 1000;
+  let mut converter = Converter::new(None);
+
+  while frame < end {
+    if stop.load(Ordering::Acquire) {
+      return false;
+    }
+
+    let frames = (end - frame).min(CHUNK_FRAMES as u64);
+    let samples = (frame..frame + frames)
+      .flat_map(|n| {
+        let sample = AMPLITUDE * (2.0 * PI * frequency * n as f64 / rate as f64).sin();
+        [sample, sample]
+      })
+      .collect();
+    frame += frames;
+
+    if let Err(why) = sink.write(&AudioPacket::Samples(samples), &mut converter) {
+      warn!("Could not write tone: {:?}", why);
+      return false;
+    }
+  }
+
+  true
+}
@@ -0,0 +1,407 @@
+use crate::lib::history::History;
+use crate::lib::now_playing::format_duration;
+use crate::lib::settings::Settings;
+
+use chrono::{Duration as ChronoDuration, Utc};
+use log::*;
+use rusqlite::{params, Connection};
+use serenity::{builder::CreateEmbed, http::Http, model::id};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration};
+
+use std::sync::Arc;
+
+const TOP: i64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+  Day,
+  Week,
+  Month,
+  All,
+}
+
+impl Period {
+  pub fn parse(s: &str) -> Option<Period> {
+    match s.to_lowercase().as_str() {
+      "day" => Some(Period::Day),
+      "week" => Some(Period::Week),
+      "month" => Some(Period::Month),
+      "all" => Some(Period::All),
+      _ => None,
+    }
+  }
+
+  /// Unix timestamp the period starts at.
+  pub fn since(&self, now: i64) -> i64 {
+    match self {
+      Period::Day => now - ChronoDuration::days(1).num_seconds(),
+      Period::Week => now - ChronoDuration::weeks(1).num_seconds(),
+      Period::Month => now - ChronoDuration::days(30).num_seconds(),
+      Period::All => 0,
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match self {
+      Period::Day => "day",
+      Period::Week => "week",
+      Period::Month => "month",
+      Period::All => "all time",
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+  pub plays: u64,
+  pub listened_ms: u64,
+  pub top_tracks: Vec<(String, Vec<String>, u64)>,
+  pub top_artists: Vec<(String, u64)>,
+  pub busiest_hours: Vec<(u32, u64)>,
+  pub listeners: Vec<(u64, u64, u64)>,
+}
+
+impl Stats {
+  pub fn query(conn: &Connection, guild_id: id::GuildId, since: i64) -> rusqlite::Result<Stats> {
+    let (plays, listened_ms) = conn.query_row(
+      "SELECT COUNT(*), COALESCE(SUM(listened_ms), 0) FROM history
+        WHERE guild_id = ?1 AND played_at >= ?2",
+      params![guild_id.0, since],
+      |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+
+    let mut statement = conn.prepare(
+      "SELECT name, artists, COUNT(*) AS plays FROM history
+        WHERE guild_id = ?1 AND played_at >= ?2
+        GROUP BY track_id ORDER BY plays DESC, SUM(listened_ms) DESC LIMIT ?3",
+    )?;
+    let top_tracks = statement
+      .query_map(params![guild_id.0, since, TOP], |row| {
+        let artists: String = row.get(1)?;
+
+        Ok((
+          row.get(0)?,
+          serde_json::from_str(&artists).unwrap_or_default(),
+          row.get(2)?,
+        ))
+      })?
+      .collect::<rusqlite::Result<_>>()?;
+
+    let mut statement = conn.prepare(
+      "SELECT artist.value, COUNT(*) AS plays FROM history, json_each(history.artists) AS artist
+        WHERE guild_id = ?1 AND played_at >= ?2
+        GROUP BY artist.value ORDER BY plays DESC LIMIT ?3",
+    )?;
+    let top_artists = statement
+      .query_map(params![guild_id.0, since, TOP], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+      })?
+      .collect::<rusqlite::Result<_>>()?;
+
+    let mut statement = conn.prepare(
+      "SELECT CAST(strftime('%H', played_at, 'unixepoch') AS INTEGER) AS hour, COUNT(*) AS plays
+        FROM history WHERE guild_id = ?1 AND played_at >= ?2
+        GROUP BY hour ORDER BY plays DESC, hour LIMIT 3",
+    )?;
+    let busiest_hours = statement
+      .query_map(params![guild_id.0, since], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+      })?
+      .collect::<rusqlite::Result<_>>()?;
+
+    let mut statement = conn.prepare(
+      "SELECT user_id, COUNT(*), SUM(listened_ms) AS listened FROM history
+        WHERE guild_id = ?1 AND played_at >= ?2 AND user_id IS NOT NULL
+        GROUP BY user_id ORDER BY listened DESC LIMIT ?3",
+    )?;
+    let listeners = statement
+      .query_map(params![guild_id.0, since, TOP], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+      })?
+      .collect::<rusqlite::Result<_>>()?;
+
+    Ok(Stats {
+      plays,
+      listened_ms,
+      top_tracks,
+      top_artists,
+      busiest_hours,
+      listeners,
+    })
+  }
+
+  pub fn embed<'a>(&self, e: &'a mut CreateEmbed, title: &str) -> &'a mut CreateEmbed {
+    e.title(title);
+    e.description(format!(
+      "`{} plays · {} listened`",
+      self.plays,
+      format_listened(self.listened_ms)
+    ));
+
+    if self.plays == 0 {
+      return e;
+    }
+
+    let tracks = self
+      .top_tracks
+      .iter()
+      .enumerate()
+      .map(|(i, (name, artists, plays))| {
+        format!(
+          "{}. **{}** – {} `{}`",
+          i + 1,
+          name,
+          artists.join(", "),
+          plays
+        )
+      })
+      .collect::<Vec<_>>()
+      .join("\n");
+    e.field("top tracks", tracks, false);
+
+    let artists = self
+      .top_artists
+      .iter()
+      .enumerate()
+      .map(|(i, (name, plays))| format!("{}. {} `{}`", i + 1, name, plays))
+      .collect::<Vec<_>>()
+      .join("\n");
+    e.field("top artists", artists, true);
+
+    let hours = self
+      .busiest_hours
+      .iter()
+      .map(|(hour, plays)| format!("{:02}:00 `{}`", hour, plays))
+      .collect::<Vec<_>>()
+      .join("\n");
+    e.field("busiest hours (utc)", hours, true);
+
+    if !self.listeners.is_empty() {
+      let listeners = self
+        .listeners
+        .iter()
+        .map(|(user_id, plays, listened_ms)| {
+          format!(
+            "<@{}> `{} plays · {}`",
+            user_id,
+            plays,
+            format_listened(*listened_ms)
+          )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+      e.field("listeners", listeners, false);
+    }
+
+    e
+  }
+}
+
+pub fn title(period: Period) -> String {
+  format!("stats · {}", period.name())
+}
+
+/// Formats long durations as `1h 23m`, short ones as `m:ss`.
+fn format_listened(ms: u64) -> String {
+  let minutes = ms / 60_000;
+
+  if minutes >= 60 {
+    format!("{}h {}m", minutes / 60, minutes % 60)
+  } else {
+    format_duration(ms as u32)
+  }
+}
+
+/// Posts a recap of the past week in every guild that configured a recap channel.
+pub async fn recap_loop(
+  http: Arc<Http>,
+  settings: Arc<Mutex<Settings>>,
+  history: Arc<Mutex<History>>,
+) {
+  let week = ChronoDuration::weeks(1).num_seconds();
+
+  loop {
+    let now = Utc::now().timestamp();
+    let guilds = settings.lock().await.guilds();
+
+    for (guild_id, guild_settings) in guilds {
+      let channel_id = match guild_settings.recap_channel {
+        Some(channel_id) => id::ChannelId(channel_id),
+        None => continue,
+      };
+
+      if now - guild_settings.last_recap.unwrap_or(0) < week {
+        continue;
+      }
+
+      let stats = match Stats::query(history.lock().await.connection(), guild_id, now - week) {
+        Ok(stats) => stats,
+        Err(why) => {
+          warn!("Could not query weekly recap: {:?}", why);
+          continue;
+        }
+      };
+
+      if let Err(why) = channel_id
+        .send_message(&http, |m| m.embed(|e| stats.embed(e, "weekly recap")))
+        .await
+      {
+        debug!("Error sending weekly recap: {:?}", why);
+        continue;
+      }
+
+      if let Err(why) = settings
+        .lock()
+        .await
+        .update(guild_id, |s| s.last_recap = Some(now))
+      {
+        warn!("Could not save settings: {:?}", why);
+      }
+    }
+
+    sleep(Duration::from_secs(60 * 60)).await;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lib::now_playing::TrackInfo;
+
+  use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+
+  const GUILD: id::GuildId = id::GuildId(1);
+  const DAY: i64 = 24 * 60 * 60;
+
+  /// Records a listen through `History`, then backdates it and sets how long it was listened to.
+  fn seed(
+    history: &mut History,
+    track: u128,
+    name: &str,
+    artists: &[&str],
+    user_id: u64,
+    played_at: i64,
+    listened_ms: u64,
+  ) {
+    let track_id = SpotifyId {
+      id: track,
+      audio_type: SpotifyAudioType::Track,
+    };
+    let info = TrackInfo {
+      name: name.to_string(),
+      artists: artists.iter().map(|artist| artist.to_string()).collect(),
+      album: String::new(),
+      cover_url: None,
+      duration_ms: 0,
+    };
+
+    history
+      .start(track_id, &info, Some(id::UserId(user_id)), GUILD)
+      .unwrap();
+    history.finish(GUILD).unwrap();
+    history
+      .connection()
+      .execute(
+        "UPDATE history SET played_at = ?1, listened_ms = ?2 WHERE id = last_insert_rowid()",
+        params![played_at, listened_ms],
+      )
+      .unwrap();
+  }
+
+  fn seeded(now: i64) -> History {
+    let mut history = History::open(":memory:").unwrap();
+
+    for _ in 0..3 {
+      seed(&mut history, 1, "one", &["a", "b"], 10, now - 60, 60_000);
+    }
+    for _ in 0..2 {
+      seed(&mut history, 2, "two", &["b"], 20, now - 3 * DAY, 120_000);
+    }
+    seed(
+      &mut history,
+      3,
+      "three",
+      &["c"],
+      10,
+      now - 60 * DAY,
+      300_000,
+    );
+
+    history
+  }
+
+  #[test]
+  fn top_tracks_by_plays() {
+    let now = Utc::now().timestamp();
+    let history = seeded(now);
+
+    let stats = Stats::query(history.connection(), GUILD, Period::All.since(now)).unwrap();
+
+    let top = stats
+      .top_tracks
+      .iter()
+      .map(|(name, _, plays)| (name.as_str(), *plays))
+      .collect::<Vec<_>>();
+    assert_eq!(top, vec![("one", 3), ("two", 2), ("three", 1)]);
+    assert_eq!(stats.top_tracks[0].1, vec!["a", "b"]);
+  }
+
+  #[test]
+  fn top_artists_count_every_artist_of_a_track() {
+    let now = Utc::now().timestamp();
+    let history = seeded(now);
+
+    let stats = Stats::query(history.connection(), GUILD, Period::All.since(now)).unwrap();
+
+    assert_eq!(
+      stats.top_artists,
+      vec![
+        ("b".to_string(), 5),
+        ("a".to_string(), 3),
+        ("c".to_string(), 1)
+      ]
+    );
+  }
+
+  #[test]
+  fn listen_time_per_period() {
+    let now = Utc::now().timestamp();
+    let history = seeded(now);
+
+    let totals = |period: Period| {
+      let stats = Stats::query(history.connection(), GUILD, period.since(now)).unwrap();
+      (stats.plays, stats.listened_ms)
+    };
+
+    assert_eq!(totals(Period::Day), (3, 180_000));
+    assert_eq!(totals(Period::Week), (5, 420_000));
+    assert_eq!(totals(Period::Month), (5, 420_000));
+    assert_eq!(totals(Period::All), (6, 720_000));
+  }
+
+  #[test]
+  fn listeners_by_time_listened() {
+    let now = Utc::now().timestamp();
+    let history = seeded(now);
+
+    let stats = Stats::query(history.connection(), GUILD, Period::All.since(now)).unwrap();
+
+    assert_eq!(stats.listeners, vec![(10, 4, 480_000), (20, 2, 240_000)]);
+  }
+
+  #[test]
+  fn empty_guild() {
+    let now = Utc::now().timestamp();
+    let history = seeded(now);
+
+    let stats = Stats::query(history.connection(), id::GuildId(2), Period::All.since(now)).unwrap();
+
+    assert_eq!(stats.plays, 0);
+    assert_eq!(stats.listened_ms, 0);
+    assert!(stats.top_tracks.is_empty());
+    assert!(stats.top_artists.is_empty());
+    assert!(stats.busiest_hours.is_empty());
+    assert!(stats.listeners.is_empty());
+  }
+}
@@ -0,0 +1,118 @@
+use crate::lib::cache::CacheConfig;
+use crate::lib::player::{DeviceConfig, SpotifyPlayer};
+
+use librespot::core::cache::Cache;
+use serenity::{model::id, prelude::TypeMapKey};
+use tokio::sync::Mutex;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Each linked user's credentials are cached in their own directory, audio is shared.
+pub fn credentials_dir(cache_dir: &Path, user_id: impl Display) -> PathBuf {
+  cache_dir.join("users").join(user_id.to_string())
+}
+
+/// Users with credentials cached by `login <discord user id>` or `!link`.
+pub fn cached_user_ids(cache_dir: &Path) -> Vec<id::UserId> {
+  let entries = match fs::read_dir(cache_dir.join("users")) {
+    Ok(entries) => entries,
+    Err(_) => return Vec::new(),
+  };
+
+  entries
+    .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<u64>().ok())
+    .map(id::UserId)
+    .collect()
+}
+
+/// Discord users linked to a Spotify account, each with their own player. Which of them drives
+/// the audio in a guild is tracked in `Guilds`.
+pub struct LinkedUsers {
+  cache: CacheConfig,
+  device: DeviceConfig,
+  players: HashMap<id::UserId, Arc<Mutex<SpotifyPlayer>>>,
+}
+
+impl LinkedUsers {
+  pub fn new(cache: CacheConfig, device: DeviceConfig) -> LinkedUsers {
+    LinkedUsers {
+      cache,
+      device,
+      players: HashMap::new(),
+    }
+  }
+
+  pub fn cache(&self) -> &CacheConfig {
+    &self.cache
+  }
+
+  pub fn cache_for(&self, user_id: id::UserId) -> Option<Cache> {
+    let cache_dir = self.cache.dir.as_ref()?;
+
+    self.cache.open(Some(credentials_dir(cache_dir, user_id)))
+  }
+
+  /// Device settings new players are created with.
+  pub fn device(&self) -> DeviceConfig {
+    self.device.clone()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.players.is_empty()
+  }
+
+  pub fn contains(&self, user_id: id::UserId) -> bool {
+    self.players.contains_key(&user_id)
+  }
+
+  pub fn get(&self, user_id: id::UserId) -> Option<Arc<Mutex<SpotifyPlayer>>> {
+    self.players.get(&user_id).cloned()
+  }
+
+  /// Returns the player this replaces, if any.
+  pub fn insert(
+    &mut self,
+    user_id: id::UserId,
+    player: Arc<Mutex<SpotifyPlayer>>,
+  ) -> Option<Arc<Mutex<SpotifyPlayer>>> {
+    self.players.insert(user_id, player)
+  }
+
+  /// Unlinks the user and forgets their cached credentials.
+  pub fn remove(&mut self, user_id: id::UserId) -> Option<Arc<Mutex<SpotifyPlayer>>> {
+    if let Some(cache_dir) = &self.cache.dir {
+      let _ = fs::remove_dir_all(credentials_dir(cache_dir, user_id));
+    }
+
+    self.players.remove(&user_id)
+  }
+
+  pub fn iter(&self) -> Vec<(id::UserId, Arc<Mutex<SpotifyPlayer>>)> {
+    self
+      .players
+      .iter()
+      .map(|(user_id, player)| (*user_id, player.clone()))
+      .collect()
+  }
+
+  /// The user's player, or any player when they are not linked. Good enough for fetching
+  /// metadata, which every session can do.
+  pub fn get_or_any(
+    &self,
+    user_id: Option<id::UserId>,
+  ) -> Option<(id::UserId, Arc<Mutex<SpotifyPlayer>>)> {
+    user_id
+      .and_then(|user_id| Some((user_id, self.get(user_id)?)))
+      .or_else(|| self.iter().into_iter().next())
+  }
+}
+
+pub struct LinkedUsersKey;
+
+impl TypeMapKey for LinkedUsersKey {
+  type Value = Arc<Mutex<LinkedUsers>>;
+}
@@ -1,8 +1,15 @@
 extern crate dotenv;
 
+use crate::lib::cache::CacheConfig;
+use crate::lib::player::{DeviceConfig, Quality};
+use crate::lib::snapshot::ResumeMode;
 use crate::logging;
+use anyhow::anyhow;
 use dotenv::dotenv;
+use librespot::core::config::{DeviceType, SessionConfig};
+use librespot::playback::config::VolumeCtrl;
 use serde::Deserialize;
+use std::path::PathBuf;
 
 #[derive(Deserialize, Debug)]
 #[serde(default)]
@@ -10,6 +17,32 @@ pub struct Config {
   pub log_level: logging::LogLevel,
   pub log_timestamps: bool,
   pub log_colored: bool,
+  pub settings_path: String,
+  pub database_path: String,
+  pub snapshot_path: String,
+  pub resume_on_start: ResumeMode,
+  /// Playback saved longer ago than this is not picked back up.
+  pub resume_max_age_hours: u64,
+  pub device_name: String,
+  pub device_type: String,
+  /// Percent, 0 to 100.
+  pub initial_volume: Option<u8>,
+  pub volume_ctrl: String,
+  pub autoplay: bool,
+  /// Tracks played this recently are not autoplayed again.
+  pub radio_repeat_hours: u64,
+  pub quality: String,
+  /// Bot activity while casting, see `Presence`.
+  pub presence_format: String,
+  pub cache_dir: Option<PathBuf>,
+  /// FLAC, MP3 and Ogg files under here can be played with `!play local:<search>`.
+  pub local_music_dir: Option<PathBuf>,
+  /// Audio cache size limit in megabytes, 0 for none.
+  pub cache_limit_mb: u64,
+  /// Advertise the device on the local network instead of logging in with a fixed account.
+  pub discovery: bool,
+  /// 0 picks a free port.
+  pub discovery_port: u16,
 }
 impl Default for Config {
   fn default() -> Self {
@@ -17,6 +50,24 @@ impl Default for Config {
       log_level: Default::default(),
       log_timestamps: true,
       log_colored: true,
+      settings_path: "settings.json".to_string(),
+      database_path: "musy.db".to_string(),
+      snapshot_path: "snapshot.json".to_string(),
+      resume_on_start: Default::default(),
+      resume_max_age_hours: 12,
+      device_name: "Discord".to_string(),
+      device_type: "audiodongle".to_string(),
+      initial_volume: None,
+      volume_ctrl: "linear".to_string(),
+      autoplay: true,
+      radio_repeat_hours: 6,
+      quality: "320".to_string(),
+      presence_format: "{artists}: {track}".to_string(),
+      cache_dir: None,
+      local_music_dir: None,
+      cache_limit_mb: 4000,
+      discovery: false,
+      discovery_port: 0,
     }
   }
 }
@@ -27,4 +78,46 @@ impl Config {
 
     Ok(envy::from_env::<Self>()?)
   }
+
+  pub fn cache(&self) -> CacheConfig {
+    CacheConfig {
+      dir: self.cache_dir.clone(),
+      limit: Some(self.cache_limit_mb * 1_000_000).filter(|limit| *limit > 0),
+    }
+  }
+
+  pub fn device(&self) -> anyhow::Result<DeviceConfig> {
+    let device_type = self
+      .device_type
+      .parse::<DeviceType>()
+      .map_err(|_| anyhow!("Unknown DEVICE_TYPE {}", self.device_type))?;
+
+    let volume_ctrl = self.volume_ctrl.parse::<VolumeCtrl>().map_err(|_| {
+      anyhow!(
+        "Unknown VOLUME_CTRL {}, expected cubic, fixed, linear or log",
+        self.volume_ctrl
+      )
+    })?;
+
+    let quality = Quality::parse(&self.quality).ok_or_else(|| {
+      anyhow!(
+        "Unknown QUALITY {}, expected auto, 96, 160 or 320",
+        self.quality
+      )
+    })?;
+
+    let initial_volume = self
+      .initial_volume
+      .map(|percent| (percent.min(100) as u32 * u16::MAX as u32 / 100) as u16);
+
+    Ok(DeviceConfig {
+      device_id: SessionConfig::default().device_id,
+      name: self.device_name.clone(),
+      device_type,
+      initial_volume,
+      volume_ctrl,
+      autoplay: self.autoplay,
+      quality,
+    })
+  }
 }
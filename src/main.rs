@@ -1,26 +1,55 @@
 mod log_config;
 mod logging;
 
-use chrono::Utc;
+use chrono::{TimeZone, Utc};
 use log::*;
 use log_config::Config;
-use std::{env, fmt::Debug, sync::Arc};
+use std::{
+  collections::HashSet,
+  env,
+  fmt::Debug,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
 
 // This trait adds the `register_songbird` and `register_songbird_with` methods to the client builder below.
 // The voice client can be retrieved in any command using `songbird::get(ctx).await`.
 use songbird::input;
-use songbird::SerenityInit;
-
-mod lib {
-  pub mod player;
-}
-
-use lib::player::{SpotifyPlayer, SpotifyPlayerKey};
-use librespot::core::mercury::MercuryError;
-use librespot::playback::config::Bitrate;
-use librespot::playback::player::PlayerEvent;
-use tokio::sync::Mutex;
-use tokio::time::{sleep, Duration};
+use songbird::{SerenityInit, Songbird};
+
+use rust_music_bot::lib;
+
+use lib::announce::{self as announce, Announcements, AnnouncementsKey};
+use lib::blocklist::Blockable;
+use lib::cache::{CacheStats, CacheStatsKey, Purge};
+use lib::discovery;
+use lib::events::{self as events, Event, GuildEventsKey, Source};
+use lib::filter::{self as filter, Filtered};
+use lib::guilds::{Guilds, GuildsKey};
+use lib::history::{ExportFormat, History, HistoryKey};
+use lib::local::{self as local, LocalLibrary, LocalLibraryKey};
+use lib::login;
+use lib::now_playing::{self as now_playing, TrackInfo};
+use lib::offline::{self as offline, Offline, OfflineKey, OfflineLibrary, PendingLogin};
+use lib::player::{ConnectState, EmittedSink, Quality, SpotifyPlayer};
+use lib::prefetch::{self as prefetch, Collection, Fetched, Progress};
+use lib::presence::{self as presence, Presence, PresenceKey};
+use lib::queue::Playable;
+use lib::radio::{Radio, RadioKey};
+use lib::router::{play_next, Hooks, Router};
+use lib::session;
+use lib::settings::{Settings, SettingsKey};
+use lib::snapshot::{self as snapshot, ResumeMode, Snapshot, Snapshots, SnapshotsKey};
+use lib::source::{AudioSource, SyntheticSource};
+use lib::stats::{self as stats, Period, Stats};
+use lib::users::{self as users, LinkedUsers, LinkedUsersKey};
+use librespot::core::cache::Cache;
+use librespot::core::session::{Session, SessionError};
+use librespot::core::spotify_id::{SpotifyAudioType, SpotifyId};
+use tokio::sync::{broadcast, Mutex};
+use tokio::time::{sleep, Duration, Instant};
 
 use serenity::{
   async_trait,
@@ -28,14 +57,20 @@ use serenity::{
   framework::{
     standard::{
       macros::{command, group},
-      CommandResult,
+      Args, CommandResult,
     },
     StandardFramework,
   },
+  http::Http,
   model::{
-    channel::Message, gateway, gateway::Ready, id, prelude::Activity, user, voice::VoiceState,
+    channel::{Message, Reaction},
+    gateway::Ready,
+    guild::Guild,
+    id,
+    prelude::Activity,
+    user,
+    voice::VoiceState,
   },
-  prelude::TypeMapKey,
   Result as SerenityResult,
 };
 
@@ -44,14 +79,17 @@ use hyper_tls::HttpsConnector;
 use serde_derive::{Deserialize, Serialize};
 
 #[group]
-#[commands(join, leave, ping, latency)]
+#[commands(
+  join, leave, ping, latency, np, announce, history, stats, recap, resume, status, link, unlink,
+  linked, device, quality, cache, prefetch, play, autoplay, block, unblock, blocklist, explicit,
+  filtered, tone
+)]
 struct General;
 
-struct Handler;
-
-pub struct UserIdKey;
-impl TypeMapKey for UserIdKey {
-  type Value = id::UserId;
+#[derive(Default)]
+struct Handler {
+  /// Set once the loops are running, the cache is ready again after every shard reconnect.
+  started: AtomicBool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -160,188 +198,240 @@ impl EventHandler for Handler {
     debug!("msg.content {:?}", msg.content);
   }
 
-  async fn cache_ready(&self, ctx: Context, guilds: Vec<id::GuildId>) {
-    let guild_id = match guilds.first() {
-      Some(guild_id) => *guild_id,
-      None => {
-        panic!("Not currently in any guilds.");
+  async fn cache_ready(&self, ctx: Context, _: Vec<id::GuildId>) {
+    if self.started.swap(true, Ordering::AcqRel) {
+      debug!("Cache ready again, players and loops are already running");
+      return;
+    }
+
+    let data = ctx.data.read().await;
+
+    let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+    let guilds = data.get::<GuildsKey>().unwrap().clone();
+    let settings = data.get::<SettingsKey>().unwrap().clone();
+    let history = data.get::<HistoryKey>().unwrap().clone();
+    let snapshots = data.get::<SnapshotsKey>().unwrap().clone();
+    let presence = data.get::<PresenceKey>().unwrap().clone();
+    let guild_events = data.get::<GuildEventsKey>().unwrap().clone();
+    let offline = data.get::<OfflineKey>().unwrap().clone();
+    let library = data.get::<LocalLibraryKey>().unwrap().clone();
+    drop(data);
+
+    let players = linked.lock().await.iter();
+    for (user_id, player) in players {
+      start_player(&ctx, user_id, player).await;
+    }
+
+    // Users whose session is down get the offline mix until it reconnects
+    let pending = offline.lock().await.pending();
+    if !pending.is_empty() {
+      for (user_id, _) in pending {
+        if let Some((guild_id, channel_id)) = voice_channel_of(&ctx, user_id).await {
+          start_offline_mix(&ctx, user_id, guild_id, channel_id).await;
+        }
+      }
+
+      tokio::spawn(reconnect_offline(ctx.clone()));
+    }
+
+    tokio::spawn(presence::update_loop(
+      ctx.clone(),
+      presence,
+      linked.clone(),
+      library,
+      guild_events.subscribe(),
+    ));
+    tokio::spawn(announce_loop(ctx.clone()));
+    tokio::spawn(history_loop(ctx.clone()));
+
+    tokio::spawn(stats::recap_loop(
+      ctx.http.clone(),
+      settings.clone(),
+      history.clone(),
+    ));
+
+    let manager = songbird::get(&ctx)
+      .await
+      .expect("Songbird Voice client placed in at initialisation.")
+      .clone();
+
+    tokio::spawn(snapshot::save_loop(snapshots.clone(), guilds, manager));
+
+    for snapshot in snapshots.load() {
+      match snapshots.resume {
+        ResumeMode::Auto => {
+          resume_snapshot(&ctx, &snapshot).await;
+        }
+        // Kept for `!resume` until it is too old
+        ResumeMode::Ask => {
+          offer_resume(&ctx, &snapshot).await;
+          continue;
+        }
+        ResumeMode::Off => {}
       }
+
+      if let Err(why) = snapshots.remove(snapshot.guild_id) {
+        warn!("Could not remove snapshot: {:?}", why);
+      }
+    }
+  }
+
+  async fn guild_create(&self, ctx: Context, guild: Guild, _: bool) {
+    let linked = ctx
+      .data
+      .read()
+      .await
+      .get::<LinkedUsersKey>()
+      .unwrap()
+      .clone();
+
+    // Linked users already in voice here can cast right away
+    for (user_id, voice_state) in &guild.voice_states {
+      let channel_id = match voice_state.channel_id {
+        Some(channel_id) => channel_id,
+        None => continue,
+      };
+
+      let player = match linked.lock().await.get(*user_id) {
+        Some(player) => player,
+        None => continue,
+      };
+
+      enable_connect(&ctx, &player, guild.id, channel_id).await;
+    }
+  }
+
+  async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+    let guild_id = match reaction.guild_id {
+      Some(guild_id) => guild_id,
+      None => return,
     };
 
-    let data = ctx.data.read().await;
+    if reaction.user_id == Some(ctx.cache.current_user_id().await) {
+      return;
+    }
 
-    let player = data.get::<SpotifyPlayerKey>().unwrap().clone();
-    let user_id = *data
-      .get::<UserIdKey>()
-      .expect("User ID placed in at initialisation.");
+    let data = ctx.data.read().await;
+    let announcements = data.get::<AnnouncementsKey>().unwrap().clone();
 
-    // Handle case when user is in VC when bot starts
-    let guild = ctx
-      .cache
-      .guild(guild_id)
+    if !announcements
+      .lock()
       .await
-      .expect("Could not find guild in cache.");
+      .is_current(guild_id, reaction.message_id)
+    {
+      return;
+    }
 
-    let channel_id = guild
-      .voice_states
-      .get(&user_id)
-      .and_then(|voice_state| voice_state.channel_id);
-    drop(guild);
+    if announce::is_reaction(&reaction.emoji, announce::SKIP) {
+      let caster = data
+        .get::<GuildsKey>()
+        .unwrap()
+        .lock()
+        .await
+        .caster(guild_id);
+      let player = match caster {
+        Some(caster) => data
+          .get::<LinkedUsersKey>()
+          .unwrap()
+          .lock()
+          .await
+          .get(caster),
+        None => None,
+      };
+
+      let player = match player {
+        Some(player) => player,
+        None => return,
+      };
+
+      if player.lock().await.state() == ConnectState::Active {
+        if let Some(spirc) = player.lock().await.spirc.as_ref() {
+          spirc.next();
+        }
+      } else {
+        // Queued with `!play`, so the queue decides what is next
+        let guilds = data.get::<GuildsKey>().unwrap().clone();
+        let history = data.get::<HistoryKey>().unwrap().clone();
+        let library = data.get::<LocalLibraryKey>().unwrap().clone();
 
-    if channel_id.is_some() {
-      // Enable casting
-      player.lock().await.enable_connect().await;
-    }
-
-    let c = ctx.clone();
-
-    // Handle Spotify events
-    tokio::spawn(async move {
-      loop {
-        let channel = player.lock().await.event_channel.clone().unwrap();
-        let mut receiver = channel.lock().await;
-
-        let event = match receiver.recv().await {
-          Some(e) => e,
-          None => {
-            // Busy waiting bad but quick and easy
-            sleep(Duration::from_millis(256)).await;
-            continue;
-          }
-        };
-
-        match event {
-          PlayerEvent::Stopped { .. } => {
-            c.set_presence(None, user::OnlineStatus::Online).await;
-
-            let manager = songbird::get(&c)
-              .await
-              .expect("Songbird Voice client placed in at initialisation.")
-              .clone();
-
-            let _ = manager.remove(guild_id).await;
-          }
-
-          PlayerEvent::Started { .. } => {
-            let manager = songbird::get(&c)
-              .await
-              .expect("Songbird Voice client placed in at initialisation.")
-              .clone();
-
-            let guild = c
-              .cache
-              .guild(guild_id)
-              .await
-              .expect("Could not find guild in cache.");
-
-            let channel_id = match guild
-              .voice_states
-              .get(&user_id)
-              .and_then(|voice_state| voice_state.channel_id)
-            {
-              Some(channel_id) => channel_id,
-              None => {
-                println!("Could not find user in VC.");
-                continue;
-              }
-            };
-
-            let _handler = manager.join(guild_id, channel_id).await;
-
-            if let Some(handler_lock) = manager.get(guild_id) {
-              let mut handler = handler_lock.lock().await;
-
-              let mut decoder = input::codec::OpusDecoderState::new().unwrap();
-              decoder.allow_passthrough = false;
-
-              let source = input::Input::new(
-                true,
-                input::reader::Reader::Extension(Box::new(
-                  player.lock().await.emitted_sink.clone(),
-                )),
-                input::codec::Codec::FloatPcm,
-                input::Container::Raw,
-                None,
-              );
-
-              handler.set_bitrate(songbird::driver::Bitrate::Auto);
-
-              handler.play_only_source(source);
-            } else {
-              println!("Could not fetch guild by ID.");
-            }
-          }
-
-          PlayerEvent::Paused { .. } => {
-            c.set_presence(None, user::OnlineStatus::Online).await;
-          }
-
-          PlayerEvent::Playing { track_id, .. } => {
-            let track: Result<librespot::metadata::Track, MercuryError> =
-              librespot::metadata::Metadata::get(&player.lock().await.session, track_id).await;
-
-            if let Ok(track) = track {
-              let artist: Result<librespot::metadata::Artist, MercuryError> =
-                librespot::metadata::Metadata::get(
-                  &player.lock().await.session,
-                  *track.artists.first().unwrap(),
-                )
-                .await;
-
-              if let Ok(artist) = artist {
-                let listening_to = format!("{}: {}", artist.name, track.name);
-
-                c.set_presence(
-                  Some(gateway::Activity::listening(listening_to)),
-                  user::OnlineStatus::Online,
-                )
-                .await;
-              }
-            }
-          }
-
-          _ => {}
+        if play_next(&player, &guilds, &history, &library, guild_id)
+          .await
+          .is_none()
+        {
+          player.lock().await.stop_direct();
         }
       }
-    });
+    } else if announce::is_reaction(&reaction.emoji, announce::LIKE) {
+      info!("{:?} liked the current track", reaction.user_id);
+    }
   }
 
   async fn voice_state_update(
     &self,
     ctx: Context,
-    _: Option<id::GuildId>,
+    guild_id: Option<id::GuildId>,
     old: Option<VoiceState>,
     new: VoiceState,
   ) {
-    let data = ctx.data.read().await;
+    let guild_id = match guild_id.or(new.guild_id) {
+      Some(guild_id) => guild_id,
+      None => return,
+    };
 
-    let user_id = data.get::<UserIdKey>();
+    let data = ctx.data.read().await;
 
-    if new.user_id.to_string() != user_id.unwrap().to_string() {
-      return;
-    }
+    let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+    let guilds = data.get::<GuildsKey>().unwrap().clone();
+    let offline = data.get::<OfflineKey>().unwrap().clone();
+    drop(data);
 
-    let player = data.get::<SpotifyPlayerKey>().unwrap();
+    let player = match linked.lock().await.get(new.user_id) {
+      Some(player) => player,
+      None => {
+        // Mutes and the like come through here too, only moving in or out matters
+        let moved = old.as_ref().and_then(|old| old.channel_id) != new.channel_id;
+        if moved && offline.lock().await.is_pending(new.user_id) {
+          offline_voice_update(&ctx, guild_id, new.user_id, new.channel_id).await;
+        }
+        return;
+      }
+    };
+    let is_caster = guilds.lock().await.caster(guild_id) == Some(new.user_id);
 
-    let guild = ctx
-      .cache
-      .guild(ctx.cache.guilds().await.first().unwrap())
-      .await
-      .unwrap();
+    let old_channel = old.as_ref().and_then(|old| old.channel_id);
 
     // If user just connected
-    if old.clone().is_none() {
+    if old.is_none() {
       // Enable casting
       ctx.set_presence(None, user::OnlineStatus::Online).await;
-      player.lock().await.enable_connect().await;
+
+      if let Some(channel_id) = new.channel_id {
+        enable_connect(&ctx, &player, guild_id, channel_id).await;
+      }
       return;
     }
 
     // If user disconnected
-    if old.clone().unwrap().channel_id.is_some() && new.channel_id.is_none() {
-      // Disable casting
-      ctx.invisible().await;
-      player.lock().await.disable_connect().await;
+    if old_channel.is_some() && new.channel_id.is_none() {
+      // Disable casting, unless they went straight into voice in another guild
+      if voice_channel_of(&ctx, new.user_id).await.is_none() {
+        player.lock().await.disable_connect().await;
+      }
+
+      // Someone else is casting here, leave them be
+      if !is_caster {
+        return;
+      }
+
+      let idle = {
+        let mut guilds = guilds.lock().await;
+        guilds.clear_caster(guild_id);
+        guilds.is_idle()
+      };
+
+      if idle {
+        ctx.invisible().await;
+      }
 
       // Disconnect
       let manager = songbird::get(&ctx)
@@ -349,180 +439,2515 @@ impl EventHandler for Handler {
         .expect("Songbird Voice client placed in at initialisation.")
         .clone();
 
-      let _handler = manager.remove(guild.id).await;
+      let _handler = manager.remove(guild_id).await;
 
       return;
     }
 
     // If user moved channels
-    if old.unwrap().channel_id.unwrap() != new.channel_id.unwrap() {
+    if is_caster && old_channel != new.channel_id {
       let bot_id = ctx.cache.current_user_id().await;
 
-      let bot_channel = guild
-        .voice_states
-        .get(&bot_id)
-        .and_then(|voice_state| voice_state.channel_id);
+      let bot_channel = ctx
+        .cache
+        .guild_field(guild_id, |guild| {
+          guild
+            .voice_states
+            .get(&bot_id)
+            .and_then(|voice_state| voice_state.channel_id)
+        })
+        .await
+        .flatten();
 
-      if Option::is_some(&bot_channel) {
+      if let (Some(_), Some(channel_id)) = (bot_channel, new.channel_id) {
         let manager = songbird::get(&ctx)
           .await
           .expect("Songbird Voice client placed in at initialisation.")
           .clone();
 
-        if let Some(guild_id) = ctx.cache.guilds().await.first() {
-          let _handler = manager.join(*guild_id, new.channel_id.unwrap()).await;
-        }
+        let _handler = manager.join(guild_id, channel_id).await;
       }
-
-      return;
     }
   }
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-  let config = Config::load()?;
-  logging::setup_logging(&config)?;
+/// Plays the offline mix in the user's voice channel, while their session is down.
+async fn start_offline_mix(
+  ctx: &Context,
+  user_id: id::UserId,
+  guild_id: id::GuildId,
+  channel_id: id::ChannelId,
+) {
+  let offline = ctx.data.read().await.get::<OfflineKey>().unwrap().clone();
+
+  let sink = match offline.lock().await.start_mix(user_id, guild_id) {
+    Some(sink) => sink,
+    None => return,
+  };
 
-  // tracing_subscriber::fmt::init();
+  let manager = songbird::get(ctx)
+    .await
+    .expect("Songbird Voice client placed in at initialisation.")
+    .clone();
+
+  join_with_sink(&manager, sink, guild_id, channel_id).await;
+}
 
-  let username =
-    env::var("SPOTIFY_USERNAME").expect("Expected a Spotify username in the environment");
-  let password =
-    env::var("SPOTIFY_PASSWORD").expect("Expected a Spotify password in the environment");
-  let user_id = env::var("DISCORD_USER_ID").expect("Expected a Discord user ID in the environment");
+/// Follows a user whose session is down in and out of voice with the offline mix.
+async fn offline_voice_update(
+  ctx: &Context,
+  guild_id: id::GuildId,
+  user_id: id::UserId,
+  channel_id: Option<id::ChannelId>,
+) {
+  let offline = ctx.data.read().await.get::<OfflineKey>().unwrap().clone();
+  let stopped = offline.lock().await.stop_mix(user_id);
+
+  if let Some(channel_id) = channel_id {
+    start_offline_mix(ctx, user_id, guild_id, channel_id).await;
+  } else if let Some(guild_id) = stopped {
+    let manager = songbird::get(ctx)
+      .await
+      .expect("Songbird Voice client placed in at initialisation.")
+      .clone();
 
-  let mut cache_dir = None;
-  if let Ok(c) = env::var("CACHE_DIR") {
-    cache_dir = Some(c);
+    let _handler = manager.remove(guild_id).await;
   }
-  let player = Arc::new(Mutex::new(
-    SpotifyPlayer::new(username, password, Bitrate::Bitrate320, cache_dir).await,
-  ));
+}
 
-  // Login with a bot token from the environment
-  let token = env::var("DISCORD_TOKEN").expect("token:");
-  let framework = StandardFramework::new()
-    // set the bot's prefix to "!"
-    .configure(|c| c.prefix("!"))
-    .group(&GENERAL_GROUP);
+/// First retry for sessions that could not connect, doubling after every failed round.
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(15);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Retries logging in users whose session could not connect, backing off up to
+/// `RECONNECT_MAX_DELAY`. Once a session is back, the user's offline mix stops and their player
+/// starts as usual.
+async fn reconnect_offline(ctx: Context) {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let offline = data.get::<OfflineKey>().unwrap().clone();
+  drop(data);
+
+  let mut delay = RECONNECT_MIN_DELAY;
+
+  loop {
+    let pending = offline.lock().await.pending();
+    if pending.is_empty() {
+      break;
+    }
 
-  info!("Starting client...");
-  let mut client = Client::builder(token)
-    .event_handler(Handler)
-    .framework(framework)
-    .type_map_insert::<SpotifyPlayerKey>(player)
-    .type_map_insert::<UserIdKey>(id::UserId::from(user_id.parse::<u64>().unwrap()))
-    .register_songbird()
-    .await
-    .expect("Error creating client");
+    sleep(delay).await;
 
-  let http = client.cache_and_http.http.clone();
-  let appinfo = http.get_current_application_info().await?;
+    for (user_id, login) in pending {
+      let device = linked.lock().await.device();
 
-  // debug!("{:#?}", appinfo);
-  info!(
-    "Connected with {} ({}). Owned by {} ({})",
-    appinfo.name,
-    appinfo.id,
-    appinfo.owner.tag(),
-    appinfo.owner.id
-  );
+      let player = match SpotifyPlayer::try_new(login.credentials, device, login.cache).await {
+        Ok(player) => Arc::new(Mutex::new(player)),
+        Err(why) => {
+          debug!("{} is still offline: {:?}", user_id, why);
+          continue;
+        }
+      };
 
-  let shard_manager = client.shard_manager.clone();
+      info!("Session of {} is back, leaving offline mode", user_id);
 
-  tokio::spawn(async move {
-    let _ = client
-      .start()
-      .await
-      .map_err(|why| println!("Client ended: {:?}", why));
-  });
+      // Voice stays connected, casting replaces the mix's source once it starts
+      offline.lock().await.reconnected(user_id);
+      let replaced = linked.lock().await.insert(user_id, player.clone());
+      if let Some(replaced) = replaced {
+        stop_player(&replaced).await;
+      }
+      start_player(&ctx, user_id, player).await;
+    }
 
-  // waits for signal to continue further
-  tokio::signal::ctrl_c().await?;
-  println!("Received Ctrl-C, shutting down.");
-  // leaves voice channel once continued
-  shard_manager.lock().await.shutdown_all().await;
+    delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+  }
+}
 
-  Ok(())
+/// Enables casting if the user is already in voice, and starts handling their player's events.
+async fn start_player(ctx: &Context, user_id: id::UserId, player: Arc<Mutex<SpotifyPlayer>>) {
+  // Subscribe before casting is enabled, so no event is missed
+  let (router, metrics) = {
+    let player = player.lock().await;
+    (player.subscribe(), player.subscribe())
+  };
+
+  if let (Some(router), Some(metrics)) = (router, metrics) {
+    tokio::spawn(route_events(ctx.clone(), user_id, player.clone(), router));
+    tokio::spawn(metrics_loop(ctx.clone(), player.clone(), metrics));
+  }
+
+  // Handle case when user is in VC when bot starts
+  if let Some((guild_id, channel_id)) = voice_channel_of(ctx, user_id).await {
+    // Enable casting
+    enable_connect(ctx, &player, guild_id, channel_id).await;
+  }
+
+  let watcher = tokio::spawn(session::watch(player.clone()));
+  player.lock().await.set_watcher(watcher);
 }
 
-#[command]
-#[only_in(guilds)]
-async fn join(ctx: &Context, msg: &Message) -> CommandResult {
-  let guild = msg.guild(&ctx.cache).await.unwrap();
-  let guild_id = guild.id;
+/// Undoes `start_player`, for a player that is unlinked or replaced.
+async fn stop_player(player: &Mutex<SpotifyPlayer>) {
+  let mut player = player.lock().await;
+  player.disable_connect().await;
+  player.stop_watcher();
+  // Ends the tasks handling their events
+  player.events.close();
+}
 
-  let channel_id = guild
-    .voice_states
-    .get(&msg.author.id)
-    .and_then(|voice_state| voice_state.channel_id);
+/// Routes the user's player events to the guild they are casting in.
+async fn route_events(
+  ctx: Context,
+  user_id: id::UserId,
+  player: Arc<Mutex<SpotifyPlayer>>,
+  receiver: broadcast::Receiver<(Source, Event)>,
+) {
+  let data = ctx.data.read().await;
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  let guild_events = data.get::<GuildEventsKey>().unwrap().clone();
+  let history = data.get::<HistoryKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  drop(data);
+
+  let router = Router {
+    user_id,
+    player: player.clone(),
+    guilds,
+    history,
+    library,
+    guild_events,
+    hooks: DiscordHooks {
+      ctx,
+      user_id,
+      player,
+    },
+  };
 
-  let connect_to = match channel_id {
-    Some(channel) => channel,
-    None => {
-      check_msg(msg.reply(ctx, "`join a voice channel first`").await);
+  router.run(receiver).await;
+}
 
-      return Ok(());
+/// Routing's way into Discord and Spotify, for one user's player.
+struct DiscordHooks {
+  ctx: Context,
+  user_id: id::UserId,
+  player: Arc<Mutex<SpotifyPlayer>>,
+}
+
+#[async_trait]
+impl Hooks for DiscordHooks {
+  async fn casting(&self, active: bool) {
+    self.player.lock().await.set_active(active);
+
+    if active {
+      // Casting picks its guild from the user's voice channel
+      cast_started(&self.ctx, self.user_id, &self.player).await;
     }
-  };
+  }
 
-  let manager = songbird::get(ctx)
-    .await
-    .expect("Songbird Client failed to initialize.")
-    .clone();
+  async fn skip_filtered(
+    &self,
+    guild_id: id::GuildId,
+    source: Source,
+    track_id: SpotifyId,
+  ) -> bool {
+    skip_filtered(&self.ctx, &self.player, guild_id, source, track_id).await
+  }
 
-  let _handler = manager.join(guild_id, connect_to).await;
+  async fn queue_ended(&self, guild_id: id::GuildId) {
+    let data = self.ctx.data.read().await;
+    let guilds = data.get::<GuildsKey>().unwrap().clone();
+    let history = data.get::<HistoryKey>().unwrap().clone();
+    drop(data);
 
-  Ok(())
+    continue_radio(&self.ctx, &self.player, &guilds, &history, guild_id).await;
+  }
+
+  async fn cast_event(&self, guild_id: id::GuildId, event: Event) {
+    let ctx = &self.ctx;
+    let settings = ctx.data.read().await.get::<SettingsKey>().unwrap().clone();
+
+    match event {
+      Event::Stopped => {
+        let guilds = ctx.data.read().await.get::<GuildsKey>().unwrap().clone();
+        guilds.lock().await.clear_caster(guild_id);
+
+        let manager = songbird::get(ctx)
+          .await
+          .expect("Songbird Voice client placed in at initialisation.")
+          .clone();
+
+        let _ = manager.remove(guild_id).await;
+      }
+
+      Event::Preloading { track_id } => {
+        debug!("Preloading {}", track_id.to_base62());
+      }
+
+      Event::Unavailable { track_id } => {
+        warn!("{} is unavailable, skipping", track_id.to_base62());
+
+        let session = self.player.lock().await.session.clone();
+        let name = TrackInfo::fetch(&session, track_id)
+          .await
+          .map(|info| info.name)
+          .unwrap_or_else(|_| "track".to_string());
+
+        if let Some(channel_id) = settings.lock().await.guild(guild_id).announce_channel {
+          check_msg(
+            id::ChannelId(channel_id)
+              .say(&ctx.http, format!("`{} is unavailable, skipping`", name))
+              .await,
+          );
+        }
+
+        // Spirc moves on to the next track by itself, skipping here would skip that one too
+      }
+
+      Event::VolumeSet { volume } => {
+        self.player.lock().await.volume = Some(volume);
+
+        if let Err(why) = settings
+          .lock()
+          .await
+          .update(guild_id, |s| s.volume = Some(volume))
+        {
+          warn!("Could not save volume: {:?}", why);
+        }
+      }
+
+      // Spirc preloads the next track in the queue itself
+      _ => {}
+    }
+  }
 }
 
-#[command]
-#[only_in(guilds)]
-async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
-  let guild = msg.guild(&ctx.cache).await.unwrap();
-  let guild_id = guild.id;
+/// Skips the track if the guild's blocklist or explicit filter keeps it from playing, letting the
+/// guild know why. Returns whether it was skipped.
+async fn skip_filtered(
+  ctx: &Context,
+  player: &Arc<Mutex<SpotifyPlayer>>,
+  guild_id: id::GuildId,
+  source: Source,
+  track_id: SpotifyId,
+) -> bool {
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  let history = data.get::<HistoryKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  drop(data);
+
+  let guild_settings = settings.lock().await.guild(guild_id);
+  let session = player.lock().await.session.clone();
+
+  let filtered = match filter::check(&session, &guild_settings, track_id).await {
+    Some(filtered) => filtered,
+    None => return false,
+  };
 
-  let manager = songbird::get(ctx)
-    .await
-    .expect("Songbird Client failed to initialize.")
-    .clone();
-  let has_handler = manager.get(guild_id).is_some();
+  info!(
+    "Skipping {} {} in {}",
+    filtered.reason(),
+    track_id.to_base62(),
+    guild_id
+  );
 
-  if has_handler {
-    if let Err(e) = manager.remove(guild_id).await {
-      check_msg(
-        msg
-          .channel_id
-          .say(&ctx.http, format!("Failed: {:?}", e))
-          .await,
-      );
+  match source {
+    Source::Connect => {
+      if let Some(spirc) = player.lock().await.spirc.as_ref() {
+        spirc.next();
+      }
+    }
+    Source::Direct => {
+      if play_next(player, &guilds, &history, &library, guild_id)
+        .await
+        .is_none()
+      {
+        player.lock().await.stop_direct();
+      }
     }
+  }
 
-    check_msg(msg.channel_id.say(&ctx.http, "`left voice channel`").await);
-  } else {
-    check_msg(msg.reply(ctx, "`not on voice`").await);
+  let name = TrackInfo::fetch(&session, track_id)
+    .await
+    .map(|info| info.name)
+    .unwrap_or_else(|_| "track".to_string());
+
+  if let Err(why) = history
+    .lock()
+    .await
+    .log_filtered(guild_id, track_id, &name, filtered.reason())
+  {
+    warn!("Could not log filtered track: {:?}", why);
   }
 
-  Ok(())
+  if let Some(channel_id) = guild_settings.announce_channel {
+    let note = match filtered {
+      Filtered::Blocked(Blockable::Track(_)) => format!("`skipped {}, it is blocked here`", name),
+      Filtered::Blocked(blockable) => format!(
+        "`skipped {}, {} is blocked here`",
+        name,
+        blockable
+          .name(&session)
+          .await
+          .unwrap_or_else(|| "its artist or album".to_string())
+      ),
+      Filtered::Explicit => format!("`skipped {}, explicit tracks are skipped here`", name),
+    };
+
+    check_msg(id::ChannelId(channel_id).say(&ctx.http, note).await);
+  }
+
+  true
 }
 
-#[command]
-async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
-  msg.reply(ctx, "`pong`").await?;
+/// Leaves out what the guild's blocklist or explicit filter would skip anyway, logging it.
+async fn filter_queue(
+  ctx: &Context,
+  session: &Session,
+  guild_id: id::GuildId,
+  ids: Vec<SpotifyId>,
+) -> Vec<SpotifyId> {
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let history = data.get::<HistoryKey>().unwrap().clone();
+  drop(data);
+
+  let guild_settings = settings.lock().await.guild(guild_id);
+
+  let mut allowed = Vec::with_capacity(ids.len());
+  for track_id in ids {
+    let filtered = match filter::check(session, &guild_settings, track_id).await {
+      Some(filtered) => filtered,
+      None => {
+        allowed.push(track_id);
+        continue;
+      }
+    };
 
-  Ok(())
+    let name = TrackInfo::fetch(session, track_id)
+      .await
+      .map(|info| info.name)
+      .unwrap_or_else(|_| track_id.to_base62());
+
+    debug!("Not queueing {} {}", filtered.reason(), name);
+
+    if let Err(why) =
+      history
+        .lock()
+        .await
+        .log_filtered(guild_id, track_id, &name, filtered.reason())
+    {
+      warn!("Could not log filtered track: {:?}", why);
+    }
+  }
+
+  allowed
 }
 
-#[command]
-async fn latency(ctx: &Context, msg: &Message) -> CommandResult {
-  let delay = (Utc::now() - msg.timestamp).num_milliseconds();
+/// Queues tracks from Spotify's autoplay station once the queue ran out, unless the guild turned
+/// autoplay off.
+async fn continue_radio(
+  ctx: &Context,
+  player: &Arc<Mutex<SpotifyPlayer>>,
+  guilds: &Arc<Mutex<Guilds>>,
+  history: &Arc<Mutex<History>>,
+  guild_id: id::GuildId,
+) {
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let radio = data.get::<RadioKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  drop(data);
+
+  let (session, default) = {
+    let player = player.lock().await;
+    (player.session.clone(), player.device().autoplay)
+  };
 
-  msg
-    .reply(ctx, &format!("`the latency is {:?}ms`", delay))
-    .await?;
+  if !settings
+    .lock()
+    .await
+    .guild(guild_id)
+    .autoplay
+    .unwrap_or(default)
+  {
+    return;
+  }
 
-  Ok(())
+  let tracks = radio.next_tracks(&session, history, guild_id).await;
+  let tracks = filter_queue(ctx, &session, guild_id, tracks).await;
+  if tracks.is_empty() {
+    return;
+  }
+
+  debug!("Autoplaying {} tracks in {}", tracks.len(), guild_id);
+  guilds.lock().await.get_mut(guild_id).queue.extend(tracks);
+
+  play_next(player, guilds, history, &library, guild_id).await;
+}
+
+/// Counts cache hits for every track the user's players load.
+async fn metrics_loop(
+  ctx: Context,
+  player: Arc<Mutex<SpotifyPlayer>>,
+  mut receiver: broadcast::Receiver<(Source, Event)>,
+) {
+  let data = ctx.data.read().await;
+  let cache_stats = data.get::<CacheStatsKey>().unwrap().clone();
+  let offline = data.get::<OfflineKey>().unwrap().clone();
+  drop(data);
+
+  while let Some((_, event)) = events::next(&mut receiver, "metrics").await {
+    if let Event::Loading { track_id } = event {
+      let (session, bitrate) = {
+        let player = player.lock().await;
+        (player.session.clone(), player.bitrate())
+      };
+      CacheStats::record(&cache_stats, &session, track_id, bitrate).await;
+      // So the track can be part of the offline mix later on
+      offline::remember(&offline, &session, track_id, bitrate).await;
+    }
+  }
+}
+
+/// Posts cast tracks in each guild's announcement channel.
+async fn announce_loop(ctx: Context) {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let announcements = data.get::<AnnouncementsKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  let mut receiver = data.get::<GuildEventsKey>().unwrap().subscribe();
+  drop(data);
+
+  // Cast or queued with `!play`, whatever plays in the guild is announced
+  while let Some(e) = events::next(&mut receiver, "announcements").await {
+    let track_id = match e.event {
+      Event::Playing { track_id, .. } => track_id,
+      Event::Changed { new_track_id } => new_track_id,
+      Event::Stopped => {
+        announcements.lock().await.reset(e.guild_id);
+        continue;
+      }
+      _ => continue,
+    };
+
+    if let Some(player) = linked.lock().await.get(e.user_id) {
+      announce_track(
+        &ctx,
+        &player,
+        &settings,
+        &announcements,
+        &library,
+        e.guild_id,
+        track_id,
+      )
+      .await;
+    }
+  }
+}
+
+/// Records what was played in each guild and for how long, cast or queued with `!play`.
+async fn history_loop(ctx: Context) {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let history = data.get::<HistoryKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  let mut receiver = data.get::<GuildEventsKey>().unwrap().subscribe();
+  drop(data);
+
+  while let Some(e) = events::next(&mut receiver, "history").await {
+    let result = match e.event {
+      Event::Playing { track_id, .. } => {
+        if let Some(player) = linked.lock().await.get(e.user_id) {
+          record_listen(&player, &history, &library, e.guild_id, track_id, e.user_id).await;
+        }
+        continue;
+      }
+      Event::Paused { .. } => history.lock().await.pause(e.guild_id),
+      Event::Stopped | Event::EndOfTrack => history.lock().await.finish(e.guild_id),
+      _ => continue,
+    };
+
+    if let Err(why) = result {
+      warn!("Could not record listened time: {:?}", why);
+    }
+  }
+}
+
+/// Casting goes to the guild the user is in voice in, taking over from anything we were playing
+/// ourselves and from other users casting there.
+async fn cast_started(ctx: &Context, user_id: id::UserId, player: &Arc<Mutex<SpotifyPlayer>>) {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  drop(data);
+
+  let (guild_id, channel_id) = match voice_channel_of(ctx, user_id).await {
+    Some(voice) => voice,
+    None => {
+      println!("Could not find user in VC.");
+      return;
+    }
+  };
+
+  player.lock().await.stop_direct();
+
+  let previous = guilds.lock().await.set_caster(guild_id, user_id);
+  let previous = match previous {
+    Some(previous) => linked.lock().await.get(previous),
+    None => None,
+  };
+
+  if let Some(previous) = previous {
+    let previous = previous.lock().await;
+    if let Some(spirc) = previous.spirc.as_ref() {
+      spirc.pause();
+    }
+    previous.stop_direct();
+  }
+
+  let manager = songbird::get(ctx)
+    .await
+    .expect("Songbird Voice client placed in at initialisation.")
+    .clone();
+
+  join_and_play(&manager, player, guild_id, channel_id).await;
+}
+
+/// Enables casting under the guild's device name. Casting already enabled under another guild's
+/// name is restarted, so the device picker shows where the audio will go. Returns whether this
+/// stopped what the player was playing, as restarting casting or switching bitrate does.
+async fn enable_connect(
+  ctx: &Context,
+  player: &Arc<Mutex<SpotifyPlayer>>,
+  guild_id: id::GuildId,
+  channel_id: id::ChannelId,
+) -> bool {
+  let settings = ctx.data.read().await.get::<SettingsKey>().unwrap().clone();
+  let guild_settings = settings.lock().await.guild(guild_id);
+
+  let channel_bitrate = ctx
+    .cache
+    .guild_channel(channel_id)
+    .await
+    .and_then(|channel| channel.bitrate);
+
+  let mut player = player.lock().await;
+  let name = guild_settings
+    .device_name
+    .unwrap_or_else(|| player.device().name.clone());
+  let bitrate = guild_settings
+    .quality
+    .unwrap_or(player.device().quality)
+    .bitrate(channel_bitrate);
+
+  if player.connect_name() == Some(name.as_str()) && player.bitrate() == bitrate {
+    return false;
+  }
+
+  let restarted = player.connect_name().is_some();
+  if restarted {
+    player.disable_connect().await;
+  }
+
+  // Rebuilding the players drops whatever the direct player had loaded
+  let rebuilt = player.bitrate() != bitrate;
+  if rebuilt {
+    debug!("Switching to {:?}", bitrate);
+    player.set_bitrate(bitrate).await;
+  }
+
+  // Pick up at the volume last set in this guild
+  if guild_settings.volume.is_some() {
+    player.volume = guild_settings.volume;
+  }
+
+  player.enable_connect(name).await;
+
+  restarted || rebuilt
+}
+
+/// Applies changed device settings to the linked users in voice here. Whatever was playing is
+/// picked back up, as restarting casting stops it.
+async fn reconnect_in_voice(ctx: &Context, guild_id: id::GuildId) {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  drop(data);
+
+  let in_voice = ctx
+    .cache
+    .guild_field(guild_id, |guild| {
+      guild
+        .voice_states
+        .iter()
+        .filter_map(|(user_id, voice_state)| Some((*user_id, voice_state.channel_id?)))
+        .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+
+  for (user_id, channel_id) in in_voice {
+    let player = match linked.lock().await.get(user_id) {
+      Some(player) => player,
+      None => continue,
+    };
+
+    let current = {
+      let guilds = guilds.lock().await;
+      guilds
+        .now_playing(guild_id)
+        .filter(|_| guilds.caster(guild_id) == Some(user_id))
+    };
+
+    if !enable_connect(ctx, &player, guild_id, channel_id).await {
+      continue;
+    }
+
+    let current = match current {
+      Some(current) => current,
+      None => continue,
+    };
+
+    // Local tracks play outside the rebuilt players and carry on by themselves, loading one into
+    // the direct player would only stop it
+    if local::is_local(current.track_id) {
+      continue;
+    }
+
+    player
+      .lock()
+      .await
+      .load(current.track_id, current.position_ms(), !current.paused);
+  }
+}
+
+/// The voice channel the user is in, looking through every guild we share with them.
+async fn voice_channel_of(
+  ctx: &Context,
+  user_id: id::UserId,
+) -> Option<(id::GuildId, id::ChannelId)> {
+  for guild_id in ctx.cache.guilds().await {
+    let channel_id = ctx
+      .cache
+      .guild_field(guild_id, |guild| {
+        guild
+          .voice_states
+          .get(&user_id)
+          .and_then(|voice_state| voice_state.channel_id)
+      })
+      .await
+      .flatten();
+
+    if let Some(channel_id) = channel_id {
+      return Some((guild_id, channel_id));
+    }
+  }
+
+  None
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+  let config = Config::load()?;
+  logging::setup_logging(&config)?;
+
+  // tracing_subscriber::fmt::init();
+
+  let cache = config.cache();
+
+  let mut args = env::args().skip(1);
+  if args.next().as_deref() == Some("login") {
+    // `login <discord user id>` links an account to that Discord user
+    let credentials_dir = match (args.next(), &cache.dir) {
+      (Some(user_id), Some(cache_dir)) => Some(users::credentials_dir(cache_dir, user_id)),
+      _ => None,
+    };
+
+    return login::login(cache.open(credentials_dir)).await;
+  }
+
+  let mut linked = LinkedUsers::new(cache.clone(), config.device()?);
+  let mut offline = Offline::new(OfflineLibrary::open(&config.database_path)?);
+  let library = match &config.local_music_dir {
+    Some(dir) => LocalLibrary::scan(dir),
+    None => LocalLibrary::default(),
+  };
+
+  if config.discovery {
+    // Whoever logs the device in plays into this user's voice channel
+    let user_id = env::var("DISCORD_USER_ID")
+      .map_err(|_| anyhow::anyhow!("Discovery needs DISCORD_USER_ID to know where to play"))?;
+
+    let device = linked.device();
+    let mut discovery = discovery::launch(&device, config.discovery_port)?;
+
+    let cache = cache.open(None);
+    let credentials = match cache.as_ref().and_then(|cache| cache.credentials()) {
+      Some(credentials) => credentials,
+      None => discovery::first_login(&mut discovery, &device).await?,
+    };
+
+    let player = SpotifyPlayer::try_new(credentials, device, cache)
+      .await
+      .map_err(|why| anyhow::anyhow!("Could not connect to Spotify: {:?}", why))?;
+    let player = Arc::new(Mutex::new(player));
+    linked.insert(id::UserId(user_id.parse()?), player.clone());
+
+    tokio::spawn(discovery::run(discovery, player));
+  } else if let Ok(user_id) = env::var("DISCORD_USER_ID") {
+    let cache = cache.open(None);
+    let credentials = login::credentials(cache.as_ref())?;
+
+    let user_id = id::UserId(user_id.parse()?);
+
+    match SpotifyPlayer::try_new(credentials.clone(), linked.device(), cache.clone()).await {
+      Ok(player) => {
+        linked.insert(user_id, Arc::new(Mutex::new(player)));
+      }
+      Err(SessionError::IoError(why)) => {
+        warn!("Spotify is unreachable, starting offline: {}", why);
+        offline.add_pending(user_id, PendingLogin { credentials, cache });
+      }
+      Err(why) => anyhow::bail!("Could not log in: {:?}", why),
+    }
+  }
+
+  if let Some(cache_dir) = &cache.dir {
+    for user_id in users::cached_user_ids(cache_dir) {
+      if linked.contains(user_id) {
+        continue;
+      }
+
+      let cache = linked.cache_for(user_id);
+      let credentials = match cache.as_ref().and_then(|cache| cache.credentials()) {
+        Some(credentials) => credentials,
+        None => continue,
+      };
+
+      match SpotifyPlayer::try_new(credentials.clone(), linked.device(), cache.clone()).await {
+        Ok(player) => {
+          linked.insert(user_id, Arc::new(Mutex::new(player)));
+        }
+        Err(SessionError::IoError(why)) => {
+          warn!(
+            "Spotify is unreachable for {}, starting offline: {}",
+            user_id, why
+          );
+          offline.add_pending(user_id, PendingLogin { credentials, cache });
+        }
+        Err(why) => warn!("Could not log in linked user {}: {:?}", user_id, why),
+      }
+    }
+  }
+
+  if linked.is_empty() && offline.pending().is_empty() {
+    anyhow::bail!("No linked users, set DISCORD_USER_ID or run `login <discord user id>`");
+  }
+  login::drop_password();
+
+  // Login with a bot token from the environment
+  let token = env::var("DISCORD_TOKEN").expect("token:");
+
+  let http = Http::new_with_token(&token);
+  let appinfo = http.get_current_application_info().await?;
+
+  // debug!("{:#?}", appinfo);
+  info!(
+    "Connected with {} ({}). Owned by {} ({})",
+    appinfo.name,
+    appinfo.id,
+    appinfo.owner.tag(),
+    appinfo.owner.id
+  );
+
+  let mut owners = HashSet::new();
+  owners.insert(appinfo.owner.id);
+
+  let framework = StandardFramework::new()
+    // set the bot's prefix to "!"
+    .configure(|c| c.prefix("!").owners(owners))
+    .group(&GENERAL_GROUP);
+
+  info!("Starting client...");
+  let mut client = Client::builder(token)
+    .event_handler(Handler::default())
+    .framework(framework)
+    .type_map_insert::<LinkedUsersKey>(Arc::new(Mutex::new(linked)))
+    .type_map_insert::<GuildsKey>(Arc::new(Mutex::new(Guilds::default())))
+    .type_map_insert::<SettingsKey>(Arc::new(Mutex::new(Settings::load(&config.settings_path))))
+    .type_map_insert::<AnnouncementsKey>(Arc::new(Mutex::new(Announcements::default())))
+    .type_map_insert::<HistoryKey>(Arc::new(Mutex::new(History::open(&config.database_path)?)))
+    .type_map_insert::<SnapshotsKey>(Arc::new(Snapshots::new(
+      &config.snapshot_path,
+      config.resume_on_start,
+      config.resume_max_age_hours,
+    )))
+    .type_map_insert::<CacheStatsKey>(Arc::new(Mutex::new(CacheStats::default())))
+    .type_map_insert::<GuildEventsKey>(events::guild_events())
+    .type_map_insert::<RadioKey>(Arc::new(Radio::new(config.radio_repeat_hours)))
+    .type_map_insert::<PresenceKey>(Arc::new(Presence::new(config.presence_format.clone())))
+    .type_map_insert::<OfflineKey>(Arc::new(Mutex::new(offline)))
+    .type_map_insert::<LocalLibraryKey>(Arc::new(library))
+    .register_songbird()
+    .await
+    .expect("Error creating client");
+
+  let shard_manager = client.shard_manager.clone();
+  let data = client.data.clone();
+
+  tokio::spawn(async move {
+    let _ = client
+      .start()
+      .await
+      .map_err(|why| println!("Client ended: {:?}", why));
+  });
+
+  // waits for signal to continue further
+  tokio::signal::ctrl_c().await?;
+  println!("Received Ctrl-C, shutting down.");
+
+  let data = data.read().await;
+  let snapshots = data.get::<SnapshotsKey>().unwrap();
+  let manager = data.get::<songbird::SongbirdKey>().unwrap();
+  let guilds = data.get::<GuildsKey>().unwrap();
+
+  let current = Snapshot::take_all(guilds, manager).await;
+  if !current.is_empty() {
+    snapshots.save(&current)?;
+  }
+  drop(data);
+
+  // leaves voice channel once continued
+  shard_manager.lock().await.shutdown_all().await;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn join(ctx: &Context, msg: &Message) -> CommandResult {
+  let guild = msg.guild(&ctx.cache).await.unwrap();
+  let guild_id = guild.id;
+
+  let channel_id = guild
+    .voice_states
+    .get(&msg.author.id)
+    .and_then(|voice_state| voice_state.channel_id);
+
+  let connect_to = match channel_id {
+    Some(channel) => channel,
+    None => {
+      check_msg(msg.reply(ctx, "`join a voice channel first`").await);
+
+      return Ok(());
+    }
+  };
+
+  let manager = songbird::get(ctx)
+    .await
+    .expect("Songbird Client failed to initialize.")
+    .clone();
+
+  let _handler = manager.join(guild_id, connect_to).await;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn leave(ctx: &Context, msg: &Message) -> CommandResult {
+  let guild = msg.guild(&ctx.cache).await.unwrap();
+  let guild_id = guild.id;
+
+  let manager = songbird::get(ctx)
+    .await
+    .expect("Songbird Client failed to initialize.")
+    .clone();
+  let has_handler = manager.get(guild_id).is_some();
+
+  if has_handler {
+    if let Err(e) = manager.remove(guild_id).await {
+      check_msg(
+        msg
+          .channel_id
+          .say(&ctx.http, format!("Failed: {:?}", e))
+          .await,
+      );
+    }
+
+    check_msg(msg.channel_id.say(&ctx.http, "`left voice channel`").await);
+  } else {
+    check_msg(msg.reply(ctx, "`not on voice`").await);
+  }
+
+  Ok(())
+}
+
+#[command]
+async fn ping(ctx: &Context, msg: &Message) -> CommandResult {
+  msg.reply(ctx, "`pong`").await?;
+
+  Ok(())
+}
+
+#[command]
+async fn latency(ctx: &Context, msg: &Message) -> CommandResult {
+  let delay = (Utc::now() - msg.timestamp).num_milliseconds();
+
+  msg
+    .reply(ctx, &format!("`the latency is {:?}ms`", delay))
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+#[aliases("nowplaying")]
+async fn np(ctx: &Context, msg: &Message) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  drop(data);
+
+  let current = match guilds.lock().await.now_playing(guild_id) {
+    Some(current) => current,
+    None => {
+      check_msg(msg.reply(ctx, "`nothing playing`").await);
+
+      return Ok(());
+    }
+  };
+
+  let player = match linked.lock().await.get_or_any(current.requested_by) {
+    Some((_, player)) => player,
+    None => return Ok(()),
+  };
+
+  let session = player.lock().await.session.clone();
+  let info = match TrackInfo::lookup(&session, &library, current.track_id).await {
+    Ok(info) => info,
+    Err(_) => {
+      check_msg(msg.reply(ctx, "`could not fetch track metadata`").await);
+
+      return Ok(());
+    }
+  };
+
+  let mut message = msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| now_playing::embed(e, &info, &current))
+    })
+    .await?;
+
+  // Keep the progress bar moving until the track changes or ends
+  let track_id = current.track_id;
+  let c = ctx.clone();
+  tokio::spawn(async move {
+    loop {
+      sleep(Duration::from_secs(10)).await;
+
+      let current = match guilds.lock().await.now_playing(guild_id) {
+        Some(current) if current.track_id == track_id => current,
+        _ => break,
+      };
+
+      if message
+        .edit(&c, |m| m.embed(|e| now_playing::embed(e, &info, &current)))
+        .await
+        .is_err()
+      {
+        break;
+      }
+
+      if current.position_ms() >= info.duration_ms {
+        break;
+      }
+    }
+  });
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn announce(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let channel_id = match args.single::<String>().as_deref() {
+    Ok("off") => None,
+    _ => Some(msg.channel_id.0),
+  };
+
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  drop(data);
+
+  settings
+    .lock()
+    .await
+    .update(guild_id, |s| s.announce_channel = channel_id)?;
+
+  let reply = match channel_id {
+    Some(_) => "`announcing tracks in this channel`",
+    None => "`announcements off`",
+  };
+  check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn device(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let device_name = match args.rest().trim() {
+    "" => None,
+    "off" => Some(None),
+    name => Some(Some(name.to_string())),
+  };
+
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  drop(data);
+
+  let device_name = match device_name {
+    Some(device_name) => device_name,
+    None => {
+      let reply = match settings.lock().await.guild(guild_id).device_name {
+        Some(name) => format!("`spotify device name is {}`", name),
+        None => format!(
+          "`spotify device name is {}`",
+          linked.lock().await.device().name
+        ),
+      };
+      check_msg(msg.reply(ctx, reply).await);
+
+      return Ok(());
+    }
+  };
+
+  settings
+    .lock()
+    .await
+    .update(guild_id, |s| s.device_name = device_name.clone())?;
+
+  // Rename the device for anyone in voice here
+  reconnect_in_voice(ctx, guild_id).await;
+
+  let reply = match device_name {
+    Some(name) => format!("`spotify device name set to {}`", name),
+    None => "`spotify device name reset`".to_string(),
+  };
+  check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn quality(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  drop(data);
+
+  let quality = match args.single::<String>() {
+    Ok(quality) => match Quality::parse(&quality) {
+      Some(quality) => quality,
+      None => {
+        check_msg(msg.reply(ctx, "`usage: !quality [auto|96|160|320]`").await);
+
+        return Ok(());
+      }
+    },
+    Err(_) => {
+      let quality = settings
+        .lock()
+        .await
+        .guild(guild_id)
+        .quality
+        .unwrap_or(linked.lock().await.device().quality);
+      check_msg(
+        msg
+          .reply(ctx, format!("`quality is {}`", quality.name()))
+          .await,
+      );
+
+      return Ok(());
+    }
+  };
+
+  settings
+    .lock()
+    .await
+    .update(guild_id, |s| s.quality = Some(quality))?;
+
+  reconnect_in_voice(ctx, guild_id).await;
+
+  check_msg(
+    msg
+      .channel_id
+      .say(&ctx.http, format!("`quality set to {}`", quality.name()))
+      .await,
+  );
+
+  Ok(())
+}
+
+/// Longest `!tone` plays for.
+const MAX_TONE_SECONDS: u32 = 60;
+
+#[command]
+#[owners_only]
+#[only_in(guilds)]
+async fn tone(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+  let seconds = args.single::<u32>().unwrap_or(5).min(MAX_TONE_SECONDS);
+
+  let channel_id = match voice_channel_of(ctx, msg.author.id).await {
+    Some((voice_guild_id, channel_id)) if voice_guild_id == guild_id => channel_id,
+    _ => {
+      check_msg(msg.reply(ctx, "`join a voice channel first`").await);
+
+      return Ok(());
+    }
+  };
+
+  let guilds = ctx.data.read().await.get::<GuildsKey>().unwrap().clone();
+  if guilds.lock().await.caster(guild_id).is_some() {
+    check_msg(msg.reply(ctx, "`something is playing here already`").await);
+
+    return Ok(());
+  }
+
+  let source = SyntheticSource::new(seconds * 1000);
+  let mut receiver = match source.subscribe() {
+    Some(receiver) => receiver,
+    None => return Ok(()),
+  };
+  let source = Arc::new(Mutex::new(source));
+
+  let manager = songbird::get(ctx)
+    .await
+    .expect("Songbird Voice client placed in at initialisation.")
+    .clone();
+
+  join_and_play(&manager, &source, guild_id, channel_id).await;
+  source
+    .lock()
+    .await
+    .load(SyntheticSource::tone_id(), 0, true);
+
+  check_msg(
+    msg
+      .reply(ctx, format!("`playing a test tone for {}s`", seconds))
+      .await,
+  );
+
+  // Leave once it is over, unless someone started playing here meanwhile
+  tokio::spawn(async move {
+    while let Some((_, event)) = events::next(&mut receiver, "tone").await {
+      debug!("Test tone: {:?}", event);
+
+      if event == Event::EndOfTrack {
+        break;
+      }
+    }
+    source.lock().await.close();
+
+    if guilds.lock().await.caster(guild_id).is_none() {
+      let _handler = manager.remove(guild_id).await;
+    }
+  });
+
+  Ok(())
+}
+
+#[command]
+#[owners_only]
+async fn cache(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let cache_stats = data.get::<CacheStatsKey>().unwrap().clone();
+  drop(data);
+
+  let cache = linked.lock().await.cache().clone();
+
+  if cache.dir.is_none() {
+    check_msg(msg.reply(ctx, "`no cache, set CACHE_DIR`").await);
+
+    return Ok(());
+  }
+
+  if args.single::<String>().ok().as_deref() == Some("purge") {
+    let purge = match args
+      .single::<String>()
+      .ok()
+      .as_deref()
+      .and_then(Purge::parse)
+    {
+      Some(purge) => purge,
+      None => {
+        check_msg(
+          msg
+            .reply(ctx, "`usage: !cache purge audio|credentials|volume`")
+            .await,
+        );
+
+        return Ok(());
+      }
+    };
+
+    let mut caches = Vec::new();
+    for (_, player) in linked.lock().await.iter() {
+      if let Some(cache) = player.lock().await.session.cache() {
+        caches.push(Cache::clone(cache));
+      }
+    }
+
+    let removed = cache.purge(purge, &caches)?;
+    check_msg(msg.reply(ctx, format!("`removed {} files`", removed)).await);
+
+    return Ok(());
+  }
+
+  let usage = cache.usage();
+  let limit = match cache.limit {
+    Some(limit) => format!("{} MB", limit / 1_000_000),
+    None => "none".to_string(),
+  };
+  let hit_rate = match cache_stats.lock().await.hit_rate() {
+    Some(hit_rate) => format!("{:.0}%", hit_rate * 100.0),
+    None => "-".to_string(),
+  };
+
+  msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| {
+        e.title("cache")
+          .field("usage", format!("{} MB", usage.bytes / 1_000_000), true)
+          .field("limit", limit, true)
+          .field("files", usage.files, true)
+          .field("hit rate", hit_rate, true)
+      })
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn play(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let library = ctx
+    .data
+    .read()
+    .await
+    .get::<LocalLibraryKey>()
+    .unwrap()
+    .clone();
+
+  // Local queries can have spaces in them, URIs cannot
+  let arg = args.rest().trim();
+  let local_id = match arg.strip_prefix("local:") {
+    Some(query) => match library.search(query) {
+      Some(local_id) => Some(local_id),
+      None => {
+        check_msg(msg.reply(ctx, "`no local track matches that`").await);
+
+        return Ok(());
+      }
+    },
+    None => None,
+  };
+
+  let playable = match local_id {
+    Some(_) => None,
+    None => match Playable::parse(arg) {
+      Some(playable) => Some(playable),
+      None => {
+        check_msg(
+          msg
+            .reply(
+              ctx,
+              "`usage: !play <track, episode or show uri, or local:<search>>`",
+            )
+            .await,
+        );
+
+        return Ok(());
+      }
+    },
+  };
+
+  let channel_id = ctx
+    .cache
+    .guild_field(guild_id, |guild| {
+      guild
+        .voice_states
+        .get(&msg.author.id)
+        .and_then(|voice_state| voice_state.channel_id)
+    })
+    .await
+    .flatten();
+
+  let channel_id = match channel_id {
+    Some(channel_id) => channel_id,
+    None => {
+      check_msg(msg.reply(ctx, "`join a voice channel first`").await);
+
+      return Ok(());
+    }
+  };
+
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  let history = data.get::<HistoryKey>().unwrap().clone();
+  drop(data);
+
+  // Queue onto whoever plays here already, otherwise the author's own account. Any other account
+  // could be casting in another guild.
+  let user_id = guilds
+    .lock()
+    .await
+    .caster(guild_id)
+    .unwrap_or(msg.author.id);
+  let player = match linked.lock().await.get(user_id) {
+    Some(player) => player,
+    None => {
+      check_msg(
+        msg
+          .reply(ctx, "`link your spotify account first, DM me !link`")
+          .await,
+      );
+
+      return Ok(());
+    }
+  };
+
+  if player.lock().await.state() == ConnectState::Active {
+    check_msg(
+      msg
+        .reply(ctx, "`casting from spotify, queue it there instead`")
+        .await,
+    );
+
+    return Ok(());
+  }
+
+  let session = player.lock().await.session.clone();
+  let ids = match (local_id, playable) {
+    (Some(local_id), _) => Some(vec![local_id]),
+    (None, Some(playable)) => playable.ids(&session).await,
+    (None, None) => None,
+  };
+  let ids = match ids {
+    Some(ids) if !ids.is_empty() => ids,
+    _ => {
+      check_msg(msg.reply(ctx, "`could not find anything to play`").await);
+
+      return Ok(());
+    }
+  };
+
+  let found = ids.len();
+  let ids = filter_queue(ctx, &session, guild_id, ids).await;
+  if ids.is_empty() {
+    let reply = match found {
+      1 => "`not queueing that, it is filtered here`",
+      _ => "`not queueing any of those, they are filtered here`",
+    };
+    check_msg(msg.reply(ctx, reply).await);
+
+    return Ok(());
+  }
+
+  let name = TrackInfo::lookup(&session, &library, ids[0])
+    .await
+    .map(|info| info.name)
+    .unwrap_or_else(|_| "it".to_string());
+  let count = ids.len();
+
+  let mut guilds_lock = guilds.lock().await;
+  let idle =
+    guilds_lock.cast_by(user_id) != Some(guild_id) || guilds_lock.now_playing(guild_id).is_none();
+
+  if !idle {
+    guilds_lock.get_mut(guild_id).queue.extend(ids);
+    drop(guilds_lock);
+
+    let reply = match count {
+      1 => format!("`queued {}`", name),
+      _ => format!("`queued {} episodes`", count),
+    };
+    check_msg(msg.reply(ctx, reply).await);
+
+    return Ok(());
+  }
+
+  let previous = guilds_lock.set_caster(guild_id, user_id);
+  guilds_lock.get_mut(guild_id).queue.extend(ids);
+  drop(guilds_lock);
+
+  let previous = match previous {
+    Some(previous) => linked.lock().await.get(previous),
+    None => None,
+  };
+
+  if let Some(previous) = previous {
+    previous.lock().await.stop_direct();
+  }
+
+  let manager = songbird::get(ctx)
+    .await
+    .expect("Songbird Voice client placed in at initialisation.")
+    .clone();
+
+  join_and_play(&manager, &player, guild_id, channel_id).await;
+  play_next(&player, &guilds, &history, &library, guild_id).await;
+
+  let reply = match count {
+    1 => format!("`playing {}`", name),
+    _ => format!("`playing {}, {} more queued`", name, count - 1),
+  };
+  check_msg(msg.reply(ctx, reply).await);
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn autoplay(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  drop(data);
+
+  let autoplay = match args.single::<String>().as_deref() {
+    Ok("on") => true,
+    Ok("off") => false,
+    Ok(_) => {
+      check_msg(msg.reply(ctx, "`usage: !autoplay [on|off]`").await);
+
+      return Ok(());
+    }
+    Err(_) => {
+      let autoplay = settings
+        .lock()
+        .await
+        .guild(guild_id)
+        .autoplay
+        .unwrap_or(linked.lock().await.device().autoplay);
+      let reply = if autoplay {
+        "`autoplay is on`"
+      } else {
+        "`autoplay is off`"
+      };
+      check_msg(msg.reply(ctx, reply).await);
+
+      return Ok(());
+    }
+  };
+
+  settings
+    .lock()
+    .await
+    .update(guild_id, |s| s.autoplay = Some(autoplay))?;
+
+  let reply = if autoplay {
+    "`autoplay on, the radio takes over once the queue runs out`"
+  } else {
+    "`autoplay off`"
+  };
+  check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn block(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  drop(data);
+
+  let current = guilds.lock().await.now_playing(guild_id);
+
+  // Without a URI, whatever is playing is blocked
+  let blockable = match args.single::<String>() {
+    Ok(uri) => Blockable::parse(&uri),
+    Err(_) => current
+      .as_ref()
+      .filter(|current| current.track_id.audio_type == SpotifyAudioType::Track)
+      .map(|current| Blockable::Track(current.track_id)),
+  };
+
+  let blockable = match blockable {
+    Some(blockable) => blockable,
+    None => {
+      check_msg(
+        msg
+          .reply(ctx, "`usage: !block [track, artist or album uri]`")
+          .await,
+      );
+
+      return Ok(());
+    }
+  };
+
+  settings.lock().await.update(guild_id, |s| {
+    s.blocked.insert(blockable.uri());
+  })?;
+
+  let player = linked.lock().await.get_or_any(Some(msg.author.id));
+  let name = match &player {
+    Some((_, player)) => {
+      let session = player.lock().await.session.clone();
+      blockable.name(&session).await
+    }
+    None => None,
+  };
+
+  check_msg(
+    msg
+      .reply(
+        ctx,
+        format!("`blocked {}`", name.unwrap_or_else(|| blockable.uri())),
+      )
+      .await,
+  );
+
+  // What is playing might be blocked now
+  if let Some(current) = current {
+    let caster = guilds.lock().await.caster(guild_id);
+    let player = match caster {
+      Some(caster) => linked.lock().await.get(caster),
+      None => None,
+    };
+
+    if let Some(player) = player {
+      let source = match player.lock().await.state() {
+        ConnectState::Active => Source::Connect,
+        _ => Source::Direct,
+      };
+
+      skip_filtered(ctx, &player, guild_id, source, current.track_id).await;
+    }
+  }
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn unblock(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let blockable = match args
+    .single::<String>()
+    .ok()
+    .as_deref()
+    .and_then(Blockable::parse)
+  {
+    Some(blockable) => blockable,
+    None => {
+      check_msg(
+        msg
+          .reply(ctx, "`usage: !unblock <track, artist or album uri>`")
+          .await,
+      );
+
+      return Ok(());
+    }
+  };
+
+  let settings = ctx.data.read().await.get::<SettingsKey>().unwrap().clone();
+
+  let mut removed = false;
+  settings.lock().await.update(guild_id, |s| {
+    removed = s.blocked.remove(&blockable.uri());
+  })?;
+
+  let reply = if removed {
+    "`unblocked`"
+  } else {
+    "`not blocked`"
+  };
+  check_msg(msg.reply(ctx, reply).await);
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn blocklist(ctx: &Context, msg: &Message) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  drop(data);
+
+  let blocked = settings.lock().await.guild(guild_id).blocked;
+  if blocked.is_empty() {
+    check_msg(msg.reply(ctx, "`nothing blocked`").await);
+
+    return Ok(());
+  }
+
+  let session = match linked.lock().await.get_or_any(Some(msg.author.id)) {
+    Some((_, player)) => Some(player.lock().await.session.clone()),
+    None => None,
+  };
+
+  let mut lines = Vec::with_capacity(blocked.len());
+  for uri in &blocked {
+    let name = match (Blockable::parse(uri), &session) {
+      (Some(blockable), Some(session)) => blockable.name(session).await,
+      _ => None,
+    };
+
+    lines.push(match name {
+      Some(name) => format!("{} `{}`", name, uri),
+      None => format!("`{}`", uri),
+    });
+  }
+
+  msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| e.title("blocked").description(lines.join("\n")))
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn explicit(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let settings = ctx.data.read().await.get::<SettingsKey>().unwrap().clone();
+
+  let skip_explicit = match args.single::<String>().as_deref() {
+    Ok("skip") => true,
+    Ok("allow") => false,
+    Ok(_) => {
+      check_msg(msg.reply(ctx, "`usage: !explicit [skip|allow]`").await);
+
+      return Ok(());
+    }
+    Err(_) => {
+      let reply = if settings.lock().await.guild(guild_id).skip_explicit {
+        "`explicit tracks are skipped`"
+      } else {
+        "`explicit tracks are allowed`"
+      };
+      check_msg(msg.reply(ctx, reply).await);
+
+      return Ok(());
+    }
+  };
+
+  settings
+    .lock()
+    .await
+    .update(guild_id, |s| s.skip_explicit = skip_explicit)?;
+
+  let reply = if skip_explicit {
+    "`skipping explicit tracks`"
+  } else {
+    "`allowing explicit tracks`"
+  };
+  check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+  Ok(())
+}
+
+const FILTERED_PAGE_SIZE: usize = 10;
+
+#[command]
+#[only_in(guilds)]
+async fn filtered(ctx: &Context, msg: &Message) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let history = ctx.data.read().await.get::<HistoryKey>().unwrap().clone();
+  let filtered = history
+    .lock()
+    .await
+    .filtered(guild_id, FILTERED_PAGE_SIZE)?;
+
+  if filtered.is_empty() {
+    check_msg(msg.reply(ctx, "`nothing filtered yet`").await);
+
+    return Ok(());
+  }
+
+  let lines = filtered
+    .iter()
+    .map(|entry| {
+      format!(
+        "`{}` {} · {}",
+        format_timestamp(entry.filtered_at),
+        entry.name,
+        entry.reason
+      )
+    })
+    .collect::<Vec<_>>();
+
+  msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| e.title("filtered").description(lines.join("\n")))
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+async fn prefetch(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let collection = match args
+    .single::<String>()
+    .ok()
+    .as_deref()
+    .and_then(Collection::parse)
+  {
+    Some(collection) => collection,
+    None => {
+      check_msg(
+        msg
+          .reply(ctx, "`usage: !prefetch <playlist or album uri>`")
+          .await,
+      );
+
+      return Ok(());
+    }
+  };
+
+  let linked = ctx
+    .data
+    .read()
+    .await
+    .get::<LinkedUsersKey>()
+    .unwrap()
+    .clone();
+
+  let player = match linked.lock().await.get_or_any(Some(msg.author.id)) {
+    Some((_, player)) => player,
+    None => return Ok(()),
+  };
+
+  let (session, bitrate) = {
+    let player = player.lock().await;
+    (player.session.clone(), player.bitrate())
+  };
+
+  let (name, tracks) = match collection.tracks(&session).await {
+    Some(collection) => collection,
+    None => {
+      check_msg(
+        msg
+          .reply(ctx, "`could not fetch that playlist or album`")
+          .await,
+      );
+
+      return Ok(());
+    }
+  };
+
+  let mut progress = Progress {
+    total: tracks.len(),
+    ..Progress::default()
+  };
+
+  let mut message = msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| prefetch::embed(e, &name, &progress))
+    })
+    .await?;
+
+  // Download one track at a time, so playback is not starved of bandwidth
+  let offline = ctx.data.read().await.get::<OfflineKey>().unwrap().clone();
+
+  let c = ctx.clone();
+  tokio::spawn(async move {
+    let mut last_update = Instant::now();
+
+    for track_id in tracks {
+      let fetched = prefetch::fetch_track(&session, track_id, bitrate).await;
+      if fetched.is_ok() {
+        offline::remember(&offline, &session, track_id, bitrate).await;
+      }
+
+      match fetched {
+        Ok(Fetched::Downloaded) => progress.downloaded += 1,
+        Ok(Fetched::AlreadyCached) => progress.cached += 1,
+        Err(why) => {
+          debug!("Could not prefetch {}: {}", track_id.to_base62(), why);
+          progress.failed += 1;
+        }
+      }
+
+      if progress.is_finished() || last_update.elapsed() >= Duration::from_secs(5) {
+        let _ = message
+          .edit(&c, |m| m.embed(|e| prefetch::embed(e, &name, &progress)))
+          .await;
+        last_update = Instant::now();
+      }
+    }
+  });
+
+  Ok(())
+}
+
+/// Posts the track in the guild's announcement channel, if one is configured.
+async fn announce_track(
+  ctx: &Context,
+  player: &Arc<Mutex<SpotifyPlayer>>,
+  settings: &Arc<Mutex<Settings>>,
+  announcements: &Arc<Mutex<Announcements>>,
+  library: &LocalLibrary,
+  guild_id: id::GuildId,
+  track_id: SpotifyId,
+) {
+  let channel_id = match settings.lock().await.guild(guild_id).announce_channel {
+    Some(channel_id) => id::ChannelId(channel_id),
+    None => return,
+  };
+
+  let session = player.lock().await.session.clone();
+
+  Announcements::announce(
+    announcements,
+    &ctx.http,
+    &session,
+    library,
+    guild_id,
+    channel_id,
+    track_id,
+  )
+  .await;
+}
+
+const HISTORY_PAGE_SIZE: usize = 10;
+
+/// Formats a stored timestamp as `mm/dd hh:mm`, empty if it is out of range.
+fn format_timestamp(timestamp: i64) -> String {
+  Utc
+    .timestamp_opt(timestamp, 0)
+    .single()
+    .map(|at| at.format("%m/%d %H:%M").to_string())
+    .unwrap_or_default()
+}
+
+#[command]
+#[only_in(guilds)]
+async fn history(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let data = ctx.data.read().await;
+  let history = data.get::<HistoryKey>().unwrap().clone();
+  drop(data);
+
+  let first = args.single::<String>().ok();
+
+  if first.as_deref() == Some("export") {
+    let format = match args
+      .single::<String>()
+      .ok()
+      .as_deref()
+      .and_then(ExportFormat::parse)
+    {
+      Some(format) => format,
+      None => {
+        check_msg(
+          msg
+            .reply(ctx, "`usage: !history export csv|json|m3u`")
+            .await,
+        );
+
+        return Ok(());
+      }
+    };
+
+    let listens = history.lock().await.all(guild_id)?;
+    let export = format.export(&listens);
+
+    msg
+      .channel_id
+      .send_files(
+        &ctx.http,
+        vec![(export.as_bytes(), format.file_name())],
+        |m| m.content(format!("`{} listens`", listens.len())),
+      )
+      .await?;
+
+    return Ok(());
+  }
+
+  let page = first
+    .and_then(|page| page.parse::<usize>().ok())
+    .unwrap_or(1)
+    .max(1);
+
+  let (listens, count) = {
+    let history = history.lock().await;
+
+    (
+      history.page(guild_id, page - 1, HISTORY_PAGE_SIZE)?,
+      history.count(guild_id)?,
+    )
+  };
+
+  if listens.is_empty() {
+    check_msg(msg.reply(ctx, "`nothing in history`").await);
+
+    return Ok(());
+  }
+
+  let lines = listens
+    .iter()
+    .map(|listen| {
+      format!(
+        "`{}` **{}** – {}",
+        format_timestamp(listen.played_at),
+        listen.name,
+        listen.artists.join(", ")
+      )
+    })
+    .collect::<Vec<_>>()
+    .join("\n");
+
+  let pages = (count + HISTORY_PAGE_SIZE - 1) / HISTORY_PAGE_SIZE;
+
+  msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| {
+        e.title("history")
+          .description(lines)
+          .footer(|f| f.text(format!("page {}/{}", page, pages)))
+      })
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn stats(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let period = match args.single::<String>() {
+    Ok(period) => match Period::parse(&period) {
+      Some(period) => period,
+      None => {
+        check_msg(msg.reply(ctx, "`usage: !stats [day|week|month|all]`").await);
+
+        return Ok(());
+      }
+    },
+    Err(_) => Period::Week,
+  };
+
+  let data = ctx.data.read().await;
+  let history = data.get::<HistoryKey>().unwrap().clone();
+  drop(data);
+
+  let since = period.since(Utc::now().timestamp());
+  let stats = Stats::query(history.lock().await.connection(), guild_id, since)?;
+
+  msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| stats.embed(e, &stats::title(period)))
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn recap(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let channel_id = match args.single::<String>().as_deref() {
+    Ok("off") => None,
+    _ => Some(msg.channel_id.0),
+  };
+
+  let data = ctx.data.read().await;
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  drop(data);
+
+  // The first recap goes out a week from now
+  settings.lock().await.update(guild_id, |s| {
+    s.recap_channel = channel_id;
+    s.last_recap = Some(Utc::now().timestamp());
+  })?;
+
+  let reply = match channel_id {
+    Some(_) => "`posting a weekly recap in this channel`",
+    None => "`weekly recap off`",
+  };
+  check_msg(msg.channel_id.say(&ctx.http, reply).await);
+
+  Ok(())
+}
+
+/// Records a new listen, or picks the current one back up when resuming after a pause.
+async fn record_listen(
+  player: &Arc<Mutex<SpotifyPlayer>>,
+  history: &Arc<Mutex<History>>,
+  library: &LocalLibrary,
+  guild_id: id::GuildId,
+  track_id: SpotifyId,
+  user_id: id::UserId,
+) {
+  {
+    let mut history = history.lock().await;
+    if history.is_current(guild_id, track_id) {
+      history.resume(guild_id);
+      return;
+    }
+
+    // Close the previous listen now, it must not keep counting if this lookup fails
+    if let Err(why) = history.finish(guild_id) {
+      warn!("Could not record listened time: {:?}", why);
+    }
+  }
+
+  let session = player.lock().await.session.clone();
+  let info = match TrackInfo::lookup(&session, library, track_id).await {
+    Ok(info) => info,
+    Err(why) => {
+      debug!("Could not fetch metadata for history: {:?}", why);
+      return;
+    }
+  };
+
+  if let Err(why) = history
+    .lock()
+    .await
+    .start(track_id, &info, Some(user_id), guild_id)
+  {
+    warn!("Could not record listen: {:?}", why);
+  }
+}
+
+#[command]
+#[owners_only]
+async fn status(ctx: &Context, msg: &Message) -> CommandResult {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let offline = data.get::<OfflineKey>().unwrap().clone();
+  drop(data);
+
+  let players = linked.lock().await.iter();
+  let pending = offline.lock().await.pending();
+
+  let mut fields = Vec::with_capacity(players.len() + pending.len());
+  for (user_id, player) in players {
+    let player = player.lock().await;
+
+    let log = player
+      .status
+      .log
+      .iter()
+      .rev()
+      .map(|(at, step)| format!("`{}` {}", at.format("%m/%d %H:%M:%S"), step))
+      .collect::<Vec<_>>()
+      .join("\n");
+
+    fields.push((
+      format!(
+        "{} · {} · {}",
+        player.session.username(),
+        player.status.state,
+        player.state()
+      ),
+      format!(
+        "<@{}> `since {} · {} reconnects`\n{}",
+        user_id,
+        player.status.since.format("%m/%d %H:%M:%S"),
+        player.status.reconnects,
+        log
+      ),
+      false,
+    ));
+  }
+
+  for (user_id, _) in pending {
+    fields.push((
+      "offline".to_string(),
+      format!(
+        "<@{}> `spotify is unreachable, retrying · playing cached tracks meanwhile`",
+        user_id
+      ),
+      false,
+    ));
+  }
+
+  msg
+    .channel_id
+    .send_message(&ctx.http, |m| m.embed(|e| e.title("status").fields(fields)))
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(dms)]
+async fn link(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let token = match args.single::<String>() {
+    Ok(token) => token,
+    Err(_) => {
+      check_msg(
+        msg
+          .reply(ctx, "`usage: !link <spotify access token>`")
+          .await,
+      );
+
+      return Ok(());
+    }
+  };
+
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  drop(data);
+
+  let user_id = msg.author.id;
+
+  if linked.lock().await.contains(user_id) {
+    check_msg(msg.reply(ctx, "`already linked, !unlink first`").await);
+
+    return Ok(());
+  }
+
+  // Logging in caches reusable credentials, so the token is not needed again
+  let (cache, device) = {
+    let linked = linked.lock().await;
+    (linked.cache_for(user_id), linked.device())
+  };
+  let credentials = login::token_credentials(String::new(), token);
+
+  let player = match SpotifyPlayer::try_new(credentials, device, cache).await {
+    Ok(player) => Arc::new(Mutex::new(player)),
+    Err(why) => {
+      debug!("Could not link {}: {:?}", user_id, why);
+      check_msg(msg.reply(ctx, "`could not log in with that token`").await);
+
+      return Ok(());
+    }
+  };
+
+  let username = player.lock().await.session.username();
+  let replaced = linked.lock().await.insert(user_id, player.clone());
+  if let Some(replaced) = replaced {
+    stop_player(&replaced).await;
+  }
+
+  start_player(ctx, user_id, player).await;
+
+  check_msg(
+    msg
+      .reply(ctx, format!("`linked spotify account {}`", username))
+      .await,
+  );
+
+  Ok(())
+}
+
+#[command]
+async fn unlink(ctx: &Context, msg: &Message) -> CommandResult {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  drop(data);
+
+  let player = linked.lock().await.remove(msg.author.id);
+
+  match player {
+    Some(player) => {
+      let mut guilds = guilds.lock().await;
+      if let Some(guild_id) = guilds.cast_by(msg.author.id) {
+        guilds.clear_caster(guild_id);
+      }
+      drop(guilds);
+
+      stop_player(&player).await;
+
+      check_msg(msg.reply(ctx, "`unlinked`").await);
+    }
+    None => check_msg(msg.reply(ctx, "`not linked`").await),
+  }
+
+  Ok(())
+}
+
+#[command]
+async fn linked(ctx: &Context, msg: &Message) -> CommandResult {
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  drop(data);
+
+  let players = linked.lock().await.iter();
+
+  let mut lines = Vec::with_capacity(players.len());
+  for (user_id, player) in players {
+    let active = match guilds.lock().await.cast_by(user_id) {
+      Some(guild_id) if Some(guild_id) == msg.guild_id => " · casting here",
+      Some(_) => " · casting",
+      None => "",
+    };
+
+    lines.push(format!(
+      "<@{}> `{}{}`",
+      user_id,
+      player.lock().await.session.username(),
+      active
+    ));
+  }
+
+  msg
+    .channel_id
+    .send_message(&ctx.http, |m| {
+      m.embed(|e| e.title("linked accounts").description(lines.join("\n")))
+    })
+    .await?;
+
+  Ok(())
+}
+
+#[command]
+#[only_in(guilds)]
+async fn resume(ctx: &Context, msg: &Message, mut args: Args) -> CommandResult {
+  let guild_id = msg.guild_id.unwrap();
+
+  let data = ctx.data.read().await;
+  let snapshots = data.get::<SnapshotsKey>().unwrap().clone();
+  drop(data);
+
+  let snapshot = match snapshots.load_guild(guild_id.0) {
+    Some(snapshot) => snapshot,
+    None => {
+      check_msg(msg.reply(ctx, "`nothing to resume`").await);
+      return Ok(());
+    }
+  };
+
+  if args.single::<String>().as_deref() == Ok("forget") {
+    snapshots.remove(guild_id.0)?;
+    check_msg(
+      msg
+        .channel_id
+        .say(&ctx.http, "`forgot the previous session`")
+        .await,
+    );
+    return Ok(());
+  }
+
+  check_msg(msg.channel_id.say(&ctx.http, "`resuming`").await);
+  if resume_snapshot(ctx, &snapshot).await {
+    snapshots.remove(guild_id.0)?;
+  }
+
+  Ok(())
+}
+
+/// Rejoins the snapshot's voice channel and continues its track from the saved position.
+async fn resume_snapshot(ctx: &Context, snapshot: &Snapshot) -> bool {
+  let track_id = match now_playing::parse_id(&snapshot.track_id) {
+    Some(track_id) => track_id,
+    None => {
+      warn!("Invalid track in snapshot: {}", snapshot.track_id);
+      return false;
+    }
+  };
+
+  let guild_id = id::GuildId(snapshot.guild_id);
+
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let guilds = data.get::<GuildsKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  drop(data);
+
+  let player = linked
+    .lock()
+    .await
+    .get_or_any(snapshot.user_id.map(id::UserId));
+
+  let (user_id, player) = match player {
+    Some(player) => player,
+    None => return false,
+  };
+
+  let mut guilds = guilds.lock().await;
+  guilds.set_caster(guild_id, user_id);
+  guilds.get_mut(guild_id).queue = snapshot
+    .queue
+    .iter()
+    .filter_map(|uri| now_playing::parse_id(uri))
+    .collect();
+  drop(guilds);
+
+  let manager = songbird::get(ctx)
+    .await
+    .expect("Songbird Voice client placed in at initialisation.")
+    .clone();
+
+  join_and_play(
+    &manager,
+    &player,
+    guild_id,
+    id::ChannelId(snapshot.voice_channel_id),
+  )
+  .await;
+
+  // Local tracks start over, they cannot be picked up mid-way
+  match library.get(track_id) {
+    Some(track) => player.lock().await.load_local(track_id, track.clone()),
+    None => player
+      .lock()
+      .await
+      .load(track_id, snapshot.position_ms, !snapshot.paused),
+  }
+
+  info!(
+    "Resumed {} at {}ms for {}",
+    snapshot.track_id, snapshot.position_ms, user_id
+  );
+
+  true
+}
+
+/// Lets the guild know a previous session can be picked back up with `!resume`.
+async fn offer_resume(ctx: &Context, snapshot: &Snapshot) {
+  let guild_id = id::GuildId(snapshot.guild_id);
+
+  let data = ctx.data.read().await;
+  let linked = data.get::<LinkedUsersKey>().unwrap().clone();
+  let settings = data.get::<SettingsKey>().unwrap().clone();
+  let library = data.get::<LocalLibraryKey>().unwrap().clone();
+  drop(data);
+
+  let channel_id = match settings.lock().await.guild(guild_id).announce_channel {
+    Some(channel_id) => Some(id::ChannelId(channel_id)),
+    None => ctx
+      .cache
+      .guild_field(guild_id, |guild| guild.system_channel_id)
+      .await
+      .flatten(),
+  };
+
+  let channel_id = match channel_id {
+    Some(channel_id) => channel_id,
+    None => {
+      info!("Previous session found, use !resume to pick it back up");
+      return;
+    }
+  };
+
+  let player = match linked
+    .lock()
+    .await
+    .get_or_any(snapshot.user_id.map(id::UserId))
+  {
+    Some((_, player)) => player,
+    None => return,
+  };
+
+  let session = player.lock().await.session.clone();
+  let track = now_playing::parse_id(&snapshot.track_id)
+    .map(|track_id| TrackInfo::lookup(&session, &library, track_id));
+  let name = match track {
+    Some(track) => track.await.map(|info| info.name).ok(),
+    None => None,
+  };
+
+  check_msg(
+    channel_id
+      .say(
+        &ctx.http,
+        format!(
+          "`{} was playing at {} before the restart, !resume to pick it back up or !resume forget to drop it`",
+          name.unwrap_or_else(|| "a track".to_string()),
+          now_playing::format_duration(snapshot.position_ms)
+        ),
+      )
+      .await,
+  );
+}
+
+/// Joins the voice channel and streams the player's audio into it.
+async fn join_and_play<P: AudioSource>(
+  manager: &Songbird,
+  player: &Arc<Mutex<P>>,
+  guild_id: id::GuildId,
+  channel_id: id::ChannelId,
+) {
+  let sink = player.lock().await.sink();
+  join_with_sink(manager, sink, guild_id, channel_id).await;
+}
+
+async fn join_with_sink(
+  manager: &Songbird,
+  sink: EmittedSink,
+  guild_id: id::GuildId,
+  channel_id: id::ChannelId,
+) {
+  let _handler = manager.join(guild_id, channel_id).await;
+
+  if let Some(handler_lock) = manager.get(guild_id) {
+    let mut handler = handler_lock.lock().await;
+
+    let mut decoder = input::codec::OpusDecoderState::new().unwrap();
+    decoder.allow_passthrough = false;
+
+    let source = input::Input::new(
+      true,
+      input::reader::Reader::Extension(Box::new(sink)),
+      input::codec::Codec::FloatPcm,
+      input::Container::Raw,
+      None,
+    );
+
+    handler.set_bitrate(songbird::driver::Bitrate::Auto);
+
+    handler.play_only_source(source);
+  } else {
+    println!("Could not fetch guild by ID.");
+  }
 }
 
 /// checks that a message successfully sent; if not, then logs why.
@@ -0,0 +1,177 @@
+use rust_music_bot::lib::events::{self as events, Event, GuildEvent, Source};
+use rust_music_bot::lib::guilds::Guilds;
+use rust_music_bot::lib::history::History;
+use rust_music_bot::lib::local::LocalLibrary;
+use rust_music_bot::lib::router::{Hooks, Router};
+use rust_music_bot::lib::source::{AudioSource, SyntheticSource};
+
+use librespot::core::spotify_id::SpotifyId;
+use serenity::async_trait;
+use serenity::model::id;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+use std::io::Read;
+use std::sync::{self, Arc};
+
+const GUILD: id::GuildId = id::GuildId(1);
+const USER: id::UserId = id::UserId(10);
+
+const TONE_MS: u32 = 200;
+
+/// Frames per resampling step, the sink holds back less than one of them.
+const RESAMPLER_FRAMES: usize = 1120;
+
+/// Records what the router hands off, nothing here casts or filters.
+#[derive(Clone, Default)]
+struct RecordedHooks {
+  queue_ended: Arc<sync::Mutex<Vec<id::GuildId>>>,
+}
+
+#[async_trait]
+impl Hooks for RecordedHooks {
+  async fn casting(&self, _active: bool) {}
+
+  async fn skip_filtered(
+    &self,
+    _guild_id: id::GuildId,
+    _source: Source,
+    _track_id: SpotifyId,
+  ) -> bool {
+    false
+  }
+
+  async fn queue_ended(&self, guild_id: id::GuildId) {
+    self.queue_ended.lock().unwrap().push(guild_id);
+  }
+
+  async fn cast_event(&self, _guild_id: id::GuildId, _event: Event) {}
+}
+
+fn track(base62: &str) -> SpotifyId {
+  SpotifyId::from_base62(base62).unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn routes_synthetic_playback_through_the_queue() {
+  let first = track("4uLU6hMCjMI75M1A2tKUQC");
+  let second = track("6rqhFgbbKwnb9MLmUQDhG6");
+
+  let source = SyntheticSource::new(TONE_MS);
+  let mut sink = source.sink();
+  let receiver = source.subscribe().unwrap();
+  let player = Arc::new(Mutex::new(source));
+
+  let guilds = Arc::new(Mutex::new(Guilds::default()));
+  {
+    let mut guilds = guilds.lock().await;
+    guilds.set_caster(GUILD, USER);
+    guilds.get_mut(GUILD).queue.push_back(second);
+  }
+
+  let guild_events = events::guild_events();
+  let mut routed = guild_events.subscribe();
+  let hooks = RecordedHooks::default();
+
+  let router = tokio::spawn(
+    Router {
+      user_id: USER,
+      player: player.clone(),
+      guilds: guilds.clone(),
+      history: Arc::new(Mutex::new(History::open(":memory:").unwrap())),
+      library: Arc::new(LocalLibrary::default()),
+      guild_events,
+      hooks: hooks.clone(),
+    }
+    .run(receiver),
+  );
+
+  // The tones block until someone reads what they wrote, as voice would
+  let samples = Arc::new(sync::Mutex::new(Vec::new()));
+  let read = samples.clone();
+  std::thread::spawn(move || {
+    let mut buffer = [0; 4096];
+    while let Ok(bytes) = sink.read(&mut buffer) {
+      let mut read = read.lock().unwrap();
+      read.extend(
+        buffer[..bytes]
+          .chunks_exact(4)
+          .map(|sample| f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]])),
+      );
+    }
+  });
+
+  player.lock().await.load(first, 0, true);
+
+  let mut seen = Vec::new();
+  while seen.len() < 6 {
+    let event = timeout(Duration::from_secs(5), routed.recv())
+      .await
+      .expect("router stopped routing")
+      .unwrap();
+    seen.push(event);
+  }
+
+  for GuildEvent {
+    guild_id,
+    user_id,
+    source,
+    ..
+  } in &seen
+  {
+    assert_eq!(
+      (*guild_id, *user_id, *source),
+      (GUILD, USER, Source::Direct)
+    );
+  }
+
+  // The second track is played off the queue once the first ends
+  let seen = seen.iter().map(|event| event.event).collect::<Vec<_>>();
+  assert_eq!(
+    seen,
+    vec![
+      Event::Loading { track_id: first },
+      Event::Playing {
+        track_id: first,
+        position_ms: 0,
+        duration_ms: TONE_MS,
+      },
+      Event::EndOfTrack,
+      Event::Loading { track_id: second },
+      Event::Playing {
+        track_id: second,
+        position_ms: 0,
+        duration_ms: TONE_MS,
+      },
+      Event::EndOfTrack,
+    ]
+  );
+
+  assert!(guilds.lock().await.now_playing(GUILD).is_none());
+  assert!(guilds.lock().await.queue(GUILD).is_empty());
+
+  // Both tones, resampled to what voice expects, short of what the resampler still holds
+  let expected = 2 * TONE_MS as usize * songbird::constants::SAMPLE_RATE_RAW / 1000;
+  let deadline = Instant::now() + Duration::from_secs(5);
+  while samples.lock().unwrap().len() / 2 < expected - RESAMPLER_FRAMES {
+    assert!(Instant::now() < deadline, "sink fell short of both tones");
+    sleep(Duration::from_millis(10)).await;
+  }
+
+  let samples = samples.lock().unwrap().clone();
+  assert!(samples.len() / 2 <= expected);
+
+  let peak = samples
+    .iter()
+    .fold(0f32, |peak, sample| peak.max(sample.abs()));
+  assert!(peak > 0.1 && peak < 0.3, "unexpected peak {}", peak);
+
+  player.lock().await.close();
+  timeout(Duration::from_secs(5), router)
+    .await
+    .expect("router kept running after the source closed")
+    .unwrap();
+
+  // Handed off once the router is done with the last end of track
+  assert_eq!(*hooks.queue_ended.lock().unwrap(), vec![GUILD]);
+}